@@ -0,0 +1,117 @@
+//! Measures WebSocket echo round-trip latency against the server binary.
+//!
+//! Starts the server on a free local port, upgrades a connection to a WebSocket and times
+//! a series of echo round trips.
+//!
+//! Run with `cargo bench --bench echo_latency`. Set `ECHO_ITERATIONS` to change the number
+//! of round trips (default 100).
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Kills the server process when the benchmark ends (or panics).
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn main() {
+    let iterations: usize = std::env::var("ECHO_ITERATIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(100);
+
+    // Pick a free port and start the server on it
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Error finding a free port.")
+        .port();
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_rust-tcp-server"))
+            .args(["127.0.0.1", &port.to_string()])
+            .current_dir(std::env::temp_dir())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Error starting server."),
+    );
+
+    let mut stream = connect(port);
+    stream.set_nodelay(true).expect("Error setting TCP_NODELAY.");
+    upgrade(&mut stream);
+
+    // Warm up
+    echo(&mut stream, "warmup");
+
+    let mut samples: Vec<Duration> = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        let started = Instant::now();
+        echo(&mut stream, &format!("message {}", i));
+        samples.push(started.elapsed());
+    }
+
+    samples.sort();
+    let total: Duration = samples.iter().sum();
+    println!("echo round trip ({} iterations)", iterations);
+    println!("  mean:   {:?}", total / iterations as u32);
+    println!("  min:    {:?}", samples[0]);
+    println!("  median: {:?}", samples[iterations / 2]);
+    println!("  p99:    {:?}", samples[(iterations * 99) / 100]);
+    println!("  max:    {:?}", samples[iterations - 1]);
+}
+
+/// Connects to the server, retrying while it starts up.
+fn connect(port: u16) -> TcpStream {
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return stream,
+            Err(error) if started.elapsed() > Duration::from_secs(10) => {
+                panic!("Error connecting to server: {}", error)
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// Performs the WebSocket opening handshake.
+fn upgrade(stream: &mut TcpStream) {
+    stream
+        .write_all(
+            b"GET /chat HTTP/1.1\r\n\
+            Host: 127.0.0.1\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .expect("Error sending upgrade request.");
+
+    let mut response: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).expect("Error reading upgrade response.");
+        response.push(byte[0]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 101"), "Upgrade was rejected.");
+}
+
+/// Sends a masked text frame and waits for the echoed reply.
+fn echo(stream: &mut TcpStream, message: &str) {
+    let mask: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+    let mut frame: Vec<u8> = vec![0b1000_0001, 0b1000_0000 | message.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(message.bytes().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    stream.write_all(&frame).expect("Error sending message.");
+
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).expect("Error reading reply.");
+    let mut payload = vec![0u8; (header[1] & 0b0111_1111) as usize];
+    stream.read_exact(&mut payload).expect("Error reading reply.");
+}
@@ -0,0 +1,52 @@
+//! A server with its own `ClientHandler`: every WebSocket message is answered in upper
+//! case, and the server stops when a client sends "ShutdownServer".
+//!
+//! Run with `cargo run --example custom_handler -- 127.0.0.1 8080`.
+
+use rust_tcp_server::{Action, ClientHandler, ConnectionInfo, DisconnectReason, ServerEvent, ServerSender, TcpServer};
+
+struct ShoutingHandler {
+    sender: ServerSender,
+}
+
+impl ClientHandler for ShoutingHandler {
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) {
+        println!("{} connected from {}", client_id, connection.peer_address);
+    }
+
+    fn on_message_received(&self, client_id: &str, message: &str) {
+        if message == "ShutdownServer" {
+            println!("{} asked the server to shut down", client_id);
+            self.sender.stop().expect("Error sending request to server.");
+            return;
+        }
+        self.sender
+            .send(client_id, Action::SendMessage(message.to_uppercase()))
+            .expect("Error sending request to server.");
+    }
+
+    fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        println!("{} disconnected ({:?})", client_id, reason);
+    }
+}
+
+fn main() {
+    let ip = std::env::args().nth(1).unwrap_or_else(|| String::from("127.0.0.1"));
+    let port = std::env::args().nth(2).unwrap_or_else(|| String::from("8080"));
+
+    let server = TcpServer::builder()
+        .address(&format!("{}:{}", ip, port))
+        .name("Shouting Server")
+        .handler_with(|sender| ShoutingHandler { sender })
+        .start()
+        .expect("Error starting server.");
+    println!("Listening on {}", server.local_addr());
+
+    // Run until a client has the server stop (and it has disconnected everyone)
+    while let Ok(event) = server.events().recv() {
+        if event == ServerEvent::Stopped {
+            break;
+        }
+    }
+    server.join().expect("Server thread panicked.");
+}
@@ -1,6 +1,135 @@
-use std::sync::mpsc::Sender;
+use crate::error::Error;
+use crate::http::{CloseCode, ConnectionInfo, DisconnectReason, HttpRequest, HttpResponse};
+#[cfg(feature = "async")]
+use std::future::Future;
 
 pub trait ClientHandler {
-    fn on_client_connected(self: &Self, client_id: &str);
-    fn on_message_received(self: &Self, client_id: &str, message: &str);
-}
\ No newline at end of file
+    /// Called when a client connects. Client ids are opaque, unique for the life of the
+    /// process and never reused; the client's address is in its connection info.
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo);
+    fn on_message_received(&self, client_id: &str, message: &str);
+
+    /// Called when a client's connection ends, for any reason.
+    fn on_client_disconnected(&self, _client_id: &str, _reason: &DisconnectReason) {}
+
+    /// Called when communication with a client fails, after which the client is disconnected,
+    /// or when a request names a client that is not connected (`Error::UnknownClient`) or
+    /// can't take it (`Error::NotWebSocket`).
+    fn on_error(&self, _client_id: &str, _error: &Error) {}
+
+    /// Called when a client asks to upgrade its HTTP connection to a WebSocket. Return `Err`
+    /// with the response to refuse it (e.g. `HttpResponse::not_found()`). By default every
+    /// upgrade is accepted.
+    fn on_websocket_upgrade_requested(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) -> Result<(), HttpResponse> {
+        Ok(())
+    }
+
+    /// Called when a client's HTTP connection is upgraded to a WebSocket. The upgrade
+    /// request carries the requested path and headers.
+    fn on_websocket_upgraded(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) {}
+
+    /// Called when a client sends a plain HTTP request. Return the response, or `None` to
+    /// answer later with `Action::Respond` (e.g. once another thread has the result). Requests
+    /// on the same connection are answered in the order they arrived. By default every request
+    /// is answered with `404 Not Found`.
+    fn on_http_request(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) -> Option<HttpResponse> {
+        Some(HttpResponse::not_found())
+    }
+
+    /// Called when a client sends a binary WebSocket message.
+    fn on_binary_message_received(&self, _client_id: &str, _data: &[u8]) {}
+
+    /// Called when a WebSocket connection is closed, with the status code and reason given
+    /// by whichever side closed it.
+    fn on_websocket_closed(&self, _client_id: &str, _code: CloseCode, _reason: &str) {}
+}
+
+/// Boxed handlers are handlers too, so handlers of different types can be used together (e.g.
+/// as the routes of a `Router<Box<dyn ClientHandler + Send + Sync>>`).
+impl<T: ClientHandler + ?Sized> ClientHandler for Box<T> {
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) {
+        (**self).on_client_connected(client_id, connection)
+    }
+
+    fn on_message_received(&self, client_id: &str, message: &str) {
+        (**self).on_message_received(client_id, message)
+    }
+
+    fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        (**self).on_client_disconnected(client_id, reason)
+    }
+
+    fn on_error(&self, client_id: &str, error: &Error) {
+        (**self).on_error(client_id, error)
+    }
+
+    fn on_websocket_upgrade_requested(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) -> Result<(), HttpResponse> {
+        (**self).on_websocket_upgrade_requested(client_id, connection, request)
+    }
+
+    fn on_websocket_upgraded(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) {
+        (**self).on_websocket_upgraded(client_id, connection, request)
+    }
+
+    fn on_http_request(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) -> Option<HttpResponse> {
+        (**self).on_http_request(client_id, connection, request)
+    }
+
+    fn on_binary_message_received(&self, client_id: &str, data: &[u8]) {
+        (**self).on_binary_message_received(client_id, data)
+    }
+
+    fn on_websocket_closed(&self, client_id: &str, code: CloseCode, reason: &str) {
+        (**self).on_websocket_closed(client_id, code, reason)
+    }
+}
+
+/// Async counterpart of `ClientHandler`, used by the async (tokio) server. Callbacks are
+/// awaited one at a time, in the order events arrive.
+#[cfg(feature = "async")]
+pub trait AsyncClientHandler: Send + Sync {
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) -> impl Future<Output = ()> + Send;
+    fn on_message_received(&self, client_id: &str, message: &str) -> impl Future<Output = ()> + Send;
+
+    /// Called when a client's connection ends, for any reason.
+    fn on_client_disconnected(&self, _client_id: &str, _reason: &DisconnectReason) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when communication with a client fails, after which the client is disconnected,
+    /// or when a request names a client that is not connected (`Error::UnknownClient`) or
+    /// can't take it (`Error::NotWebSocket`).
+    fn on_error(&self, _client_id: &str, _error: &Error) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when a client asks to upgrade its HTTP connection to a WebSocket. Return `Err`
+    /// with the response to refuse it. By default every upgrade is accepted.
+    fn on_websocket_upgrade_requested(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) -> impl Future<Output = Result<(), HttpResponse>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called when a client's HTTP connection is upgraded to a WebSocket. The upgrade
+    /// request carries the requested path and headers.
+    fn on_websocket_upgraded(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when a client sends a plain HTTP request. Return the response, or `None` to
+    /// answer later with `Action::Respond`. Requests on the same connection are answered in the
+    /// order they arrived. By default every request is answered with `404 Not Found`.
+    fn on_http_request(&self, _client_id: &str, _connection: &ConnectionInfo, _request: &HttpRequest) -> impl Future<Output = Option<HttpResponse>> + Send {
+        async { Some(HttpResponse::not_found()) }
+    }
+
+    /// Called when a client sends a binary WebSocket message.
+    fn on_binary_message_received(&self, _client_id: &str, _data: &[u8]) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when a WebSocket connection is closed, with the status code and reason given
+    /// by whichever side closed it.
+    fn on_websocket_closed(&self, _client_id: &str, _code: CloseCode, _reason: &str) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
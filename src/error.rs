@@ -0,0 +1,71 @@
+use std::fmt;
+use crate::http::request::ParseError;
+
+/// Errors reported by the server.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from, writing to or setting up a socket failed.
+    Io(std::io::Error),
+    /// A WebSocket client sent something that breaks the protocol (e.g. an unmasked frame or an
+    /// invalid close code). The connection is failed with a close frame.
+    Protocol(String),
+    /// A client sent a malformed HTTP request.
+    Http(ParseError),
+    /// The other end of a channel between the server's threads has gone (e.g. the server has
+    /// stopped).
+    ChannelClosed,
+    /// A request named a client the server does not know.
+    UnknownClient(String),
+    /// A request that only WebSocket clients can take (a message, or joining a room) named a
+    /// client that is not one.
+    NotWebSocket(String),
+    /// The server was started without a handler.
+    MissingHandler,
+    /// The server was started with an unusable setting.
+    InvalidConfig(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Http(error) => write!(f, "Malformed HTTP request: {}", error),
+            Error::ChannelClosed => write!(f, "Channel closed"),
+            Error::UnknownClient(client_id) => write!(f, "Unknown client: {}", client_id),
+            Error::NotWebSocket(client_id) => write!(f, "Not a WebSocket client: {}", client_id),
+            Error::MissingHandler => write!(f, "A handler is required to start a server"),
+            Error::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Http(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::Http(error)
+    }
+}
+
+impl<T> From<std::sync::mpsc::SendError<T>> for Error {
+    fn from(_: std::sync::mpsc::SendError<T>) -> Self {
+        Error::ChannelClosed
+    }
+}
@@ -1,17 +1,116 @@
-use crate::client_handler::ClientHandler;
-use crate::http::{Action, Request};
+use rust_tcp_server::ClientHandler;
+#[cfg(feature = "async")]
+use rust_tcp_server::AsyncClientHandler;
+use rust_tcp_server::{
+    Action, ClientInfo, CloseCode, ConnectionInfo, DisconnectReason, Error, HttpRequest, HttpResponse, RouteRequest,
+    Router, ServerSender,
+};
+#[cfg(feature = "async")]
+use rust_tcp_server::Request;
 use log::debug;
-use std::sync::mpsc::Sender;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::UnboundedSender;
 
 pub struct MyServerImpl {
     name: String,
-    to_server_tx: Sender<Request>,
+    to_server_tx: ServerSender,
 }
 
 impl MyServerImpl {
-    pub fn new(name: String, to_server_tx: Sender<Request>) -> MyServerImpl {
+    pub fn new(name: String, to_server_tx: ServerSender) -> MyServerImpl {
         MyServerImpl { name, to_server_tx }
     }
+
+    /// Serves the example routes, with this handler answering WebSocket clients on `/chat`.
+    pub fn into_router(self) -> Router<MyServerImpl> {
+        let sender = self.to_server_tx.clone();
+        routes(self, move |client_id| {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(LATER_DELAY);
+                sender
+                    .respond(&client_id, later_response())
+                    .expect("Error sending request to server.");
+            });
+        })
+    }
+}
+
+/// Answers the `Clients` command with the number of clients the server has.
+fn clients_reply(clients: &[ClientInfo]) -> Action {
+    Action::SendMessage(format!("Clients: {}", clients.len()))
+}
+
+/// Carries out the client's command, otherwise echoes the message back (`Clients` is handled
+/// by the handlers, as the answer comes later). Commands are:
+///
+/// * `Close` - closes the connection.
+/// * `Broadcast <message>` - relays the message to the other clients.
+/// * `Join <room>` / `Leave <room>` - joins or leaves a room.
+/// * `Publish <room> <message>` - sends the message to everyone in the room.
+/// * `ShutdownServer` - stops the server.
+fn reply_to(message: &str) -> Action {
+    if message == "Close" {
+        Action::Close(CloseCode::Normal, String::from("Goodbye"))
+    } else if message == "ShutdownServer" {
+        Action::Stop
+    } else if let Some(broadcast) = message.strip_prefix("Broadcast ") {
+        Action::BroadcastExcept(String::from(broadcast))
+    } else if let Some(room) = message.strip_prefix("Join ") {
+        Action::JoinRoom(String::from(room))
+    } else if let Some(room) = message.strip_prefix("Leave ") {
+        Action::LeaveRoom(String::from(room))
+    } else if let Some((room, published)) = message
+        .strip_prefix("Publish ")
+        .and_then(|publish| publish.split_once(' '))
+    {
+        Action::Publish(String::from(room), String::from(published))
+    } else {
+        Action::SendMessage(format!("Echo: {}", message))
+    }
+}
+
+/// How long `GET /later` takes to answer.
+const LATER_DELAY: Duration = Duration::from_millis(100);
+
+/// The example server's routes:
+///
+/// * `GET /` and `POST /` - `200 OK` with no body.
+/// * `POST /echo` - echoes the request body back.
+/// * `GET /later` - answered by `answer_later` (given the client's id) after a short delay.
+/// * `GET /users/:id` - greets the user, with the `greeting` query parameter if there is one.
+/// * `GET /files/*path` - names the requested file.
+/// * `/chat` - WebSocket clients, handled by `handler`.
+fn routes<H>(handler: H, answer_later: impl Fn(String) + Send + Sync + 'static) -> Router<H> {
+    Router::new()
+        .get("/", |_| Some(HttpResponse::new(200)))
+        .post("/", |_| Some(HttpResponse::new(200)))
+        .post("/echo", |routed| {
+            let request = routed.request;
+            let content_type = request.headers.get("Content-Type").unwrap_or("application/octet-stream");
+            Some(HttpResponse::new(200).with_body(content_type, request.body.clone()))
+        })
+        .get("/later", move |routed| {
+            answer_later(String::from(routed.client_id));
+            None
+        })
+        .get("/users/:id", greet_user)
+        .get("/files/*path", |routed| {
+            Some(HttpResponse::text(&format!("File: {}", routed.param("path").unwrap_or_default())))
+        })
+        .websocket("/chat", handler)
+}
+
+fn greet_user(routed: &RouteRequest) -> Option<HttpResponse> {
+    let greeting = routed.query_param("greeting").unwrap_or("Hello");
+    let id = routed.param("id").unwrap_or_default();
+    Some(HttpResponse::text(&format!("{}, user {}", greeting, id)))
+}
+
+/// The delayed answer to `GET /later`.
+fn later_response() -> HttpResponse {
+    HttpResponse::text("Sorry for the wait")
 }
 
 impl ClientHandler for MyServerImpl {
@@ -21,22 +120,183 @@ impl ClientHandler for MyServerImpl {
     ///
     /// * `self` - The server handling the new client connection.
     /// * `client_id` - The unique id of the new client.
-    /// * `sender` - A channel used to send messages to the newly connected client.
-    fn on_client_connected(self: &Self, client_id: &str) {
-        debug!("(ExtImpl) New client connected. Client id: {}", client_id);
+    /// * `connection` - The new client's address and when it connected.
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) {
+        debug!(
+            "(ExtImpl) [{}] New client connected. Client id: {}, Address: {}",
+            self.name, client_id, connection.peer_address
+        );
+    }
+
+    fn on_message_received(&self, client_id: &str, message: &str) {
+        debug!(
+            "(ExtImpl) [{}] Message received from client {}: {}",
+            self.name, client_id, message
+        );
+
+        if message == "Clients" {
+            // Handlers run on the server's thread, so wait for the list on another one
+            let sender = self.to_server_tx.clone();
+            let client_id = String::from(client_id);
+            std::thread::spawn(move || {
+                let clients = sender.list_clients().expect("Error sending request to server.");
+                sender
+                    .send(&client_id, clients_reply(&clients))
+                    .expect("Error sending request to server.");
+            });
+            return;
+        }
+
+        self.to_server_tx
+            .send(client_id, reply_to(message))
+            .expect("Error sending request to server.");
+        //self.to_server_tx.send(String::from("Send"));
+    }
+
+    fn on_binary_message_received(&self, client_id: &str, data: &[u8]) {
+        debug!(
+            "(ExtImpl) [{}] Binary message received from client {}: {} bytes",
+            self.name, client_id, data.len()
+        );
+
+        // Echo the data back
+        self.to_server_tx
+            .send(client_id, Action::SendBinary(data.to_vec()))
+            .expect("Error sending request to server.");
+    }
+
+    fn on_websocket_closed(&self, client_id: &str, code: CloseCode, reason: &str) {
+        debug!(
+            "(ExtImpl) [{}] Client {} closed the connection. Code: {}, Reason: {}",
+            self.name, client_id, code.as_u16(), reason
+        );
+    }
+
+    fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        debug!(
+            "(ExtImpl) [{}] Client {} disconnected. Reason: {:?}",
+            self.name, client_id, reason
+        );
+    }
+
+    fn on_error(&self, client_id: &str, error: &Error) {
+        debug!("(ExtImpl) [{}] Error communicating with client {}: {}", self.name, client_id, error);
+    }
+
+    fn on_websocket_upgraded(&self, client_id: &str, _connection: &ConnectionInfo, request: &HttpRequest) {
+        debug!(
+            "(ExtImpl) [{}] Client {} upgraded to WebSocket. Path: {}, Headers: {:?}",
+            self.name, client_id, request.path, request.headers
+        );
+    }
+}
+
+/// The same server as `MyServerImpl`, for the async server.
+#[cfg(feature = "async")]
+pub struct MyAsyncServerImpl {
+    name: String,
+    to_server_tx: UnboundedSender<Request>,
+}
+
+#[cfg(feature = "async")]
+impl MyAsyncServerImpl {
+    pub fn new(name: String, to_server_tx: UnboundedSender<Request>) -> MyAsyncServerImpl {
+        MyAsyncServerImpl { name, to_server_tx }
+    }
+
+    /// Serves the example routes, with this handler answering WebSocket clients on `/chat`.
+    pub fn into_router(self) -> Router<MyAsyncServerImpl> {
+        let to_server_tx = self.to_server_tx.clone();
+        routes(self, move |client_id| {
+            let to_server_tx = to_server_tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(LATER_DELAY).await;
+                to_server_tx.send(Request {
+                    client_id,
+                    action: Action::Respond(later_response()),
+                }).expect("Error sending request to server.");
+            });
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncClientHandler for MyAsyncServerImpl {
+    async fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) {
+        debug!(
+            "(ExtImpl) [{}] New client connected. Client id: {}, Address: {}",
+            self.name, client_id, connection.peer_address
+        );
     }
 
-    fn on_message_received(self: &Self, client_id: &str, message: &str) {
+    async fn on_message_received(&self, client_id: &str, message: &str) {
         debug!(
-            "(ExtImpl) Message received from client {}: {}",
-            client_id, message
+            "(ExtImpl) [{}] Message received from client {}: {}",
+            self.name, client_id, message
         );
 
-        // Echo the message back
+        if message == "Clients" {
+            let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+            self.to_server_tx.send(Request {
+                client_id: String::new(),
+                action: Action::ListClients(reply_tx),
+            }).expect("Error sending request to server.");
+
+            // Wait for the list without holding up the runtime
+            let to_server_tx = self.to_server_tx.clone();
+            let client_id = String::from(client_id);
+            tokio::task::spawn_blocking(move || {
+                if let Ok(clients) = reply_rx.recv() {
+                    to_server_tx.send(Request {
+                        client_id,
+                        action: clients_reply(&clients),
+                    }).expect("Error sending request to server.");
+                }
+            });
+            return;
+        }
+
         self.to_server_tx.send(Request {
             client_id: String::from(client_id),
-            action: Action::SendMessage(String::from(format!("Echo: {}", message))),
+            action: reply_to(message),
         }).expect("Error sending request to server.");
-        //self.to_server_tx.send(String::from("Send"));
+    }
+
+    async fn on_binary_message_received(&self, client_id: &str, data: &[u8]) {
+        debug!(
+            "(ExtImpl) [{}] Binary message received from client {}: {} bytes",
+            self.name, client_id, data.len()
+        );
+
+        // Echo the data back
+        self.to_server_tx.send(Request {
+            client_id: String::from(client_id),
+            action: Action::SendBinary(data.to_vec()),
+        }).expect("Error sending request to server.");
+    }
+
+    async fn on_websocket_closed(&self, client_id: &str, code: CloseCode, reason: &str) {
+        debug!(
+            "(ExtImpl) [{}] Client {} closed the connection. Code: {}, Reason: {}",
+            self.name, client_id, code.as_u16(), reason
+        );
+    }
+
+    async fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        debug!(
+            "(ExtImpl) [{}] Client {} disconnected. Reason: {:?}",
+            self.name, client_id, reason
+        );
+    }
+
+    async fn on_error(&self, client_id: &str, error: &Error) {
+        debug!("(ExtImpl) [{}] Error communicating with client {}: {}", self.name, client_id, error);
+    }
+
+    async fn on_websocket_upgraded(&self, client_id: &str, _connection: &ConnectionInfo, request: &HttpRequest) {
+        debug!(
+            "(ExtImpl) [{}] Client {} upgraded to WebSocket. Path: {}, Headers: {:?}",
+            self.name, client_id, request.path, request.headers
+        );
     }
 }
@@ -1,8 +1,30 @@
-pub mod tcp_client_handler;
-pub mod http_request_handler;
-pub mod websocket_request_handler;
+pub(crate) mod tcp_client_handler;
+pub(crate) mod client_session;
+pub(crate) mod http_request_handler;
+pub(crate) mod websocket_request_handler;
+pub mod websocket_codec;
 pub mod request;
 pub mod response;
+pub mod router;
 mod tcp_server;
+mod tcp_server_builder;
+mod server_handle;
+mod rooms;
+mod server_state;
+mod worker_pool;
+#[cfg(feature = "async")]
+mod async_tcp_server;
 
-pub use tcp_server::{TcpServer, Request, Action};
\ No newline at end of file
+pub use tcp_server::{TcpServer, Request, Action, ClientInfo, ServerEvent, ShutdownStatus};
+pub use tcp_server_builder::{TcpServerBuilder, ServerConfig};
+pub use server_handle::{ServerHandle, ServerSender};
+pub use http_request_handler::HttpConfig;
+pub use websocket_request_handler::WebSocketConfig;
+pub use websocket_codec::CloseCode;
+pub use tcp_client_handler::{ConnectionInfo, DisconnectReason, TcpClientType};
+pub use request::{Headers, HttpRequest};
+pub use response::HttpResponse;
+pub use router::{RouteRequest, Router};
+pub use worker_pool::WorkerPoolConfig;
+#[cfg(feature = "async")]
+pub use async_tcp_server::AsyncTcpServer;
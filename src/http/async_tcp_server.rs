@@ -0,0 +1,367 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use super::client_session::{new_client_id, ClientSession};
+use super::response;
+use super::server_state::{ClientLink, Effect, Notification, ServerState};
+use super::tcp_client_handler::{ClientEvent, DisconnectReason, FLUSH_TIMEOUT};
+use super::tcp_server::{Request, ServerEvent, ShutdownStatus, ACCEPT_ERROR_BACKOFF};
+use super::http_request_handler::HttpConfig;
+use super::websocket_request_handler::WebSocketConfig;
+use crate::client_handler::AsyncClientHandler;
+use crate::error::Error;
+
+/// The server's link to a client's task.
+struct AsyncTcpClient {
+    /// Number identifying the link.
+    link: usize,
+    to_client_tx: UnboundedSender<ClientMessage>,
+    /// The task driving the client's connection.
+    task: JoinHandle<()>
+}
+
+/// A client whose task has finished has already reported its disconnect (or is about to), so
+/// failing to reach it is not an error.
+impl ClientLink for AsyncTcpClient {
+    fn key(&self) -> usize {
+        self.link
+    }
+
+    fn send(&self, request: Request) -> Result<(), Error> {
+        if self.to_client_tx.send(ClientMessage::Request(request)).is_err() {
+            debug!("[Server] Client task {0} has already finished.", self.link);
+        }
+        Ok(())
+    }
+
+    fn send_shared(&self, _client_ids: Vec<String>, message: Arc<str>) -> Result<(), Error> {
+        if self.to_client_tx.send(ClientMessage::Shared(message)).is_err() {
+            debug!("[Server] Client task {0} has already finished.", self.link);
+        }
+        Ok(())
+    }
+}
+
+/// Messages sent from the server to a client's task.
+enum ClientMessage {
+    /// Carry out a request for the client.
+    Request(Request),
+    /// Send a text message that is shared with other clients.
+    Shared(Arc<str>),
+}
+
+/**
+ * Represents a TCP server that runs on a tokio runtime.
+ *
+ * Behaves like `TcpServer`, but each connection is a task rather than being handled by a
+ * worker thread, and the application is notified through an `AsyncClientHandler`.
+ */
+pub struct AsyncTcpServer<H: AsyncClientHandler> {
+    pub address: String,
+    pub name: String,
+    pub handler: H,
+    pub main_to_server_rx: UnboundedReceiver<Request>,
+    pub server_to_main_tx: UnboundedSender<ServerEvent>,
+    pub http_config: HttpConfig,
+    pub websocket_config: WebSocketConfig,
+    /// Most connections the server will hold open at once. Connections beyond this are
+    /// refused with `503 Service Unavailable`.
+    pub max_connections: usize,
+    /// How long a stopping server waits for its clients to disconnect before closing the
+    /// remaining connections itself.
+    pub drain_timeout: Duration,
+}
+
+impl<H: AsyncClientHandler> AsyncTcpServer<H> {
+    /**
+     * Binds to the server's address and serves clients until the server is stopped.
+     */
+    pub async fn run(self) -> Result<ShutdownStatus, Error> {
+        let listener = TcpListener::bind(&self.address).await?;
+        Ok(self.serve(listener).await)
+    }
+
+    /**
+     * Serves clients accepted on the given listener until the server is stopped.
+     */
+    pub async fn serve(mut self, listener: TcpListener) -> ShutdownStatus {
+        // Every client reports its events on a single channel
+        let (client_to_server_tx, mut client_to_server_rx) =
+            unbounded_channel::<(String, ClientEvent)>();
+
+        debug!("[Server] ({0}) listening on {1}", &self.name, &self.address);
+
+        let mut server_running: bool = true;
+        let mut main_connected: bool = true;
+        let mut state: ServerState<AsyncTcpClient> = ServerState::new(&self.name, self.max_connections);
+        // Number given to the next client's link
+        let mut next_link: usize = 0;
+
+        while server_running {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, address)) => {
+                        // Refuse connections once the server is full
+                        if state.is_full() {
+                            warn!(
+                                "[Server] ({0}) Refusing connection from {1}. Connection limit ({2}) reached.",
+                                self.name, address, self.max_connections
+                            );
+                            tokio::spawn(refuse_connection(stream));
+                            continue;
+                        }
+
+                        // Hand off to a new client task
+                        let (server_to_client_tx, server_to_client_rx) = unbounded_channel::<ClientMessage>();
+                        let client_id = new_client_id();
+                        let session = ClientSession::new(
+                            client_id.clone(),
+                            address,
+                            &self.http_config,
+                            self.websocket_config.clone()
+                        );
+                        let task = tokio::spawn(run_client(
+                            stream,
+                            session,
+                            client_to_server_tx.clone(),
+                            server_to_client_rx
+                        ));
+
+                        // Track the client (the server only passively keeps track of it)
+                        let client = AsyncTcpClient {
+                            link: next_link,
+                            to_client_tx: server_to_client_tx,
+                            task
+                        };
+                        next_link += 1;
+                        state.add_client(client_id, address, client);
+                    }
+                    // The connection was reset before it was accepted, but others may be waiting
+                    Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionAborted => {}
+                    Err(e) => {
+                        warn!(
+                            "[Server] ({0}) Error accepting client connection. Error: {1}",
+                            self.name, e
+                        );
+                        // Back off rather than spin while the error lasts (e.g. out of file
+                        // descriptors)
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    }
+                },
+                // The server holds a sender itself, so the channel never closes
+                Some((client_id, event)) = client_to_server_rx.recv() => {
+                    let effects = state.handle_client_event(client_id, event);
+                    self.apply(&mut state, effects).await;
+                },
+                request = self.main_to_server_rx.recv(), if main_connected => match request {
+                    Some(request) => {
+                        let effects = state.handle_request(request);
+                        if self.apply(&mut state, effects).await {
+                            server_running = false;
+                        }
+                    }
+                    // Nobody is left to send requests
+                    None => main_connected = false,
+                },
+            }
+        }
+
+        // Stop accepting connections
+        drop(listener);
+
+        // Ask clients to disconnect
+        let effects = state.stop_clients();
+        self.apply(&mut state, effects).await;
+
+        // Give clients until the end of the drain period to finish disconnecting
+        let drain_deadline = tokio::time::Instant::now() + self.drain_timeout;
+        while state.active_connections() > 0 {
+            debug!("Waiting for client disconnects.");
+            match tokio::time::timeout_at(drain_deadline, client_to_server_rx.recv()).await {
+                Ok(Some((client_id, event))) => {
+                    let effects = state.handle_client_event(client_id, event);
+                    self.apply(&mut state, effects).await;
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let status = match state.active_connections() {
+            0 => ShutdownStatus::Drained,
+            remaining => {
+                warn!(
+                    "[Server] ({0}) {1} client(s) did not disconnect in time. Closing their connections.",
+                    self.name, remaining
+                );
+                ShutdownStatus::ForceClosed(remaining)
+            }
+        };
+
+        // Close the connections that remain
+        for (client_id, client) in state.take_clients() {
+            client.task.abort();
+            self.handler.on_client_disconnected(&client_id, &DisconnectReason::ServerShutdown).await;
+        }
+
+        // Indicate to the main thread that this server has stopped
+        if self.server_to_main_tx.send(ServerEvent::Stopped).is_err() {
+            warn!("[Server] ({0}) Error sending ServerStopped message to main thread.", self.name);
+        }
+
+        status
+    }
+
+    /**
+     * Carries out the effects of a request or client event. Returns whether the server has
+     * been asked to stop.
+     */
+    async fn apply(&self, state: &mut ServerState<AsyncTcpClient>, effects: Vec<Effect>) -> bool {
+        let mut stop = false;
+        let mut effects = VecDeque::from(effects);
+        while let Some(effect) = effects.pop_front() {
+            match effect {
+                Effect::Notify(client_id, notification) => {
+                    effects.extend(self.notify(state, &client_id, notification).await);
+                }
+                Effect::Stop => stop = true,
+            }
+        }
+        stop
+    }
+
+    /**
+     * Notifies the handler about a client, returning the effects of its answer (if any).
+     */
+    async fn notify(
+        &self,
+        state: &mut ServerState<AsyncTcpClient>,
+        client_id: &str,
+        notification: Notification
+    ) -> Vec<Effect> {
+        let handler = &self.handler;
+        match notification {
+            Notification::Connected(connection) => handler.on_client_connected(client_id, &connection).await,
+            Notification::Message(message) => handler.on_message_received(client_id, &message).await,
+            Notification::Binary(data) => handler.on_binary_message_received(client_id, &data).await,
+            Notification::Closed(code, reason) => handler.on_websocket_closed(client_id, code, &reason).await,
+            Notification::UpgradeRequested(connection, request) => {
+                // Let the handler accept or refuse the upgrade
+                let decision = handler.on_websocket_upgrade_requested(client_id, &connection, &request).await;
+                return state.answer_upgrade(client_id, &request, decision);
+            }
+            Notification::Upgraded(connection, request) => {
+                handler.on_websocket_upgraded(client_id, &connection, &request).await
+            }
+            Notification::HttpRequest(connection, request) => {
+                // Let the handler answer now or later
+                if let Some(response) = handler.on_http_request(client_id, &connection, &request).await {
+                    return state.respond(client_id, response);
+                }
+            }
+            Notification::Error(error) => handler.on_error(client_id, &error).await,
+            Notification::Disconnected(reason) => handler.on_client_disconnected(client_id, &reason).await,
+        }
+        Vec::new()
+    }
+}
+
+/**
+ * Drives a client's session over its stream until the connection ends.
+ */
+async fn run_client(
+    mut stream: TcpStream,
+    mut session: ClientSession,
+    to_server_tx: UnboundedSender<(String, ClientEvent)>,
+    mut from_server_rx: UnboundedReceiver<ClientMessage>
+) {
+    let client_id = session.client_id().to_string();
+    let mut buffer = [0u8; 4096];
+
+    // Mark client as connected
+    session.connect();
+
+    loop {
+        // Send whatever the session has queued for the client. Once the client has been
+        // disconnected, it only gets a limited time to take the rest.
+        if !session.write_buffer().is_empty() {
+            let is_connected = session.is_connected();
+            let write = stream.write_all(session.write_buffer());
+            if is_connected {
+                if let Err(error) = write.await {
+                    session.handle_error(Error::Io(error));
+                }
+            } else {
+                match tokio::time::timeout(FLUSH_TIMEOUT, write).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => debug!(
+                        "[TCP Client Handler] ({0}) Error sending the last bytes. Error: {1}", client_id, error
+                    ),
+                    Err(_) => warn!(
+                        "[TCP Client Handler] ({0}) Client did not take the last bytes in time. Dropping them.",
+                        client_id
+                    ),
+                }
+            }
+            session.write_buffer().clear();
+        }
+
+        // Notify the server of the session's events
+        for event in session.take_events() {
+            if to_server_tx.send((client_id.clone(), event)).is_err() {
+                debug!("[TCP Client Handler] ({0}) Server has gone.", client_id);
+            }
+        }
+
+        if !session.is_connected() {
+            break;
+        }
+
+        let next_tick = session.next_tick();
+        tokio::select! {
+            read = stream.read(&mut buffer) => match read {
+                Ok(0) => session.handle_disconnect(DisconnectReason::ClosedByClient),
+                Ok(size) => session.handle_request(&buffer, &size),
+                Err(error) => session.handle_error(Error::Io(error)),
+            },
+            message = from_server_rx.recv() => match message {
+                Some(ClientMessage::Request(request)) => session.handle_server_request(request),
+                Some(ClientMessage::Shared(message)) => session.send_message(&message),
+                // The server has gone
+                None => session.handle_disconnect(DisconnectReason::ServerShutdown),
+            },
+            _ = sleep_until(next_tick) => session.handle_tick(),
+        }
+    }
+
+    if let Err(error) = stream.shutdown().await {
+        debug!("[TCP Client Handler] ({0}) Error shutting down stream. Error: {1}", client_id, error);
+    }
+
+    // Finalize disconnect
+    let _ = to_server_tx.send((client_id, session.finish()));
+}
+
+/**
+ * Sleeps until the given deadline, or forever if there isn't one.
+ */
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/**
+ * Refuses a connection because the server is at capacity.
+ */
+async fn refuse_connection(mut stream: TcpStream) {
+    let refusal = response::service_unavailable().to_bytes();
+    if stream.write_all(&refusal).await.is_ok() {
+        let _ = stream.shutdown().await;
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use log::{debug, warn};
+use super::request::HttpRequest;
+use super::http_request_handler::{HttpClientRequestHandler, HttpConfig};
+use super::tcp_client_handler::{ClientEvent, DisconnectReason, TcpClientAction, TcpClientRequestHandler};
+use super::websocket_request_handler::{WebSocketClientRequestHandler, WebSocketConfig};
+use super::websocket_codec::CloseCode;
+use crate::error::Error;
+use crate::http::{Request, Action};
+
+/// Source of client ids, shared by every server in the process.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns an id for a new client. Ids are opaque to applications and never reused.
+pub fn new_client_id() -> String {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed).to_string()
+}
+
+/**
+ * The protocol state of a single client connection, independent of how its stream is driven.
+ *
+ * The session never touches the stream itself: bytes read from the client are passed in,
+ * bytes to send are collected in the write buffer and events for the server are queued, so
+ * the same logic serves both the threaded and the async server.
+ */
+pub struct ClientSession {
+    client_id: String,
+    address: std::net::SocketAddr,
+    is_connected: bool,
+    request_handler: Box<dyn TcpClientRequestHandler + Send>,
+    websocket_config: WebSocketConfig,
+    disconnect_reason: Option<DisconnectReason>,
+    /// Bytes waiting to be written to the client.
+    write_buffer: Vec<u8>,
+    /// Events waiting to be sent to the server.
+    events: Vec<ClientEvent>
+}
+
+impl ClientSession {
+    pub fn new(
+        client_id: String,
+        address: std::net::SocketAddr,
+        http_config: &HttpConfig,
+        websocket_config: WebSocketConfig,
+    ) -> ClientSession {
+        ClientSession {
+            client_id,
+            address,
+            is_connected: false,
+            request_handler: Box::new(HttpClientRequestHandler::new(address, websocket_config.enabled, http_config)),
+            websocket_config,
+            disconnect_reason: None,
+            write_buffer: Vec::new(),
+            events: Vec::new()
+        }
+    }
+
+    /// Returns the id the server knows this client by.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn address(&self) -> std::net::SocketAddr {
+        self.address
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    /// Bytes waiting to be written to the client. Remove whatever has been written.
+    pub fn write_buffer(&mut self) -> &mut Vec<u8> {
+        &mut self.write_buffer
+    }
+
+    /// Whether bytes are still waiting to be written to the client.
+    pub fn has_pending_writes(&self) -> bool {
+        !self.write_buffer.is_empty()
+    }
+
+    /// Takes the events waiting to be sent to the server.
+    pub fn take_events(&mut self) -> Vec<ClientEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /**
+     * Marks the client as connected.
+     */
+    pub fn connect(&mut self) {
+        debug!(
+            "[TCP Client Handler] New client connection from {0}",
+            &self.address
+        );
+        self.is_connected = true;
+        self.events.push(ClientEvent::Connected);
+    }
+
+    /**
+     * Finishes the session, returning the event that tells the server the client has gone.
+     */
+    pub fn finish(mut self) -> ClientEvent {
+        let reason = self.disconnect_reason.take().unwrap_or(DisconnectReason::ClosedByClient);
+        ClientEvent::Disconnected(reason)
+    }
+
+    /**
+     * Handles bytes read from the client.
+     */
+    pub fn handle_request(&mut self, data: &[u8], size: &usize) {
+        let actions = self.request_handler.handle_request(&mut self.write_buffer, data, size);
+        self.handle_actions(actions);
+    }
+
+    /**
+     * Returns when the session next needs a tick (if ever).
+     */
+    pub fn next_tick(&self) -> Option<Instant> {
+        self.request_handler.next_tick()
+    }
+
+    /**
+     * Gives the request handler a chance to do time-based work.
+     */
+    pub fn handle_tick(&mut self) {
+        if self.is_connected {
+            let actions = self.request_handler.handle_tick();
+            self.handle_actions(actions);
+        }
+    }
+
+    /**
+     * Handles a request the server has queued for this client.
+     */
+    pub fn handle_server_request(&mut self, request: Request) {
+        if !self.is_connected {
+            return;
+        }
+
+        match request.action {
+            Action::SendMessage(message)
+            | Action::Broadcast(message)
+            | Action::BroadcastExcept(message)
+            | Action::Multicast(_, message)
+            | Action::Publish(_, message) => {
+                debug!("[Client @ {0}] Received notification from server to send a message.", self.address);
+                self.send_message(&message);
+            }
+            // Room membership and the client list are kept by the server
+            Action::JoinRoom(_) | Action::LeaveRoom(_) | Action::ListClients(_) => {}
+            Action::Respond(response) => {
+                debug!("[Client @ {0}] Received notification from server to send an HTTP response.", self.address);
+                let actions = self.request_handler.send_http_response(&mut self.write_buffer, response);
+                self.handle_actions(actions);
+            }
+            Action::SendBinary(data) => {
+                debug!("[Client @ {0}] Received notification from server to send a binary message.", self.address);
+                if let Err(error) = (*self.request_handler).send_binary_response(&mut self.write_buffer, data) {
+                    self.handle_error(Error::Io(error));
+                }
+            }
+            Action::Close(code, reason) => {
+                debug!("[Client @ {0}] Received notification from server to close the connection.", self.address);
+                let actions = self.request_handler.close_connection(code, &reason);
+                if actions.is_empty() {
+                    self.handle_disconnect(DisconnectReason::ClosedByServer(reason));
+                } else {
+                    // Remember why the connection is closing (the client will answer the close frame)
+                    self.disconnect_reason = Some(DisconnectReason::ClosedByServer(reason));
+                }
+                self.handle_actions(actions);
+            }
+            Action::Stop => {
+                debug!(
+                    "[Client @ {0}] Received notification from server to disconnect.",
+                    self.address
+                );
+                // Let WebSocket clients know the server is going away
+                let actions = self.request_handler
+                    .close_connection(CloseCode::GoingAway, "Server shutting down");
+                if actions.is_empty() {
+                    // Nothing to wait for, so mark the client as disconnected
+                    self.handle_disconnect(DisconnectReason::ServerShutdown);
+                } else {
+                    // The connection ends once the client answers the close frame (or the
+                    // close times out)
+                    self.disconnect_reason = Some(DisconnectReason::ServerShutdown);
+                }
+                self.handle_actions(actions);
+            }
+        }
+    }
+
+    /**
+     * Sends a text message to the client.
+     */
+    pub fn send_message(&mut self, message: &str) {
+        if !self.is_connected {
+            return;
+        }
+        if let Err(error) = (*self.request_handler).send_response(&mut self.write_buffer, message) {
+            self.handle_error(Error::Io(error));
+        }
+    }
+
+    /**
+     * Handles client disconnect.
+     */
+    pub fn handle_disconnect(&mut self, reason: DisconnectReason) {
+        debug!("[TCP Client Handler] ({0}) Disconnected. Reason: {1:?}", &self.address, reason);
+        self.is_connected = false;
+        // The first reason wins (e.g. a server close followed by the client's reply)
+        if self.disconnect_reason.is_none() {
+            self.disconnect_reason = Some(reason);
+        }
+    }
+
+    /**
+     * Handles errors communicating with the client (e.g. reading from or writing to its
+     * stream, or a malformed request). Only this client's connection is closed.
+     */
+    pub fn handle_error(&mut self, error: Error) {
+        warn!("[TCP Client Handler] ({0}) Error: {1}", &self.address, error);
+        // Mark the client as disconnected
+        self.handle_disconnect(DisconnectReason::Error(error.to_string()));
+        // Inform the server of the error
+        self.events.push(ClientEvent::Error(error));
+    }
+
+    /**
+     * Carries out the actions requested by the request handler.
+     */
+    fn handle_actions(&mut self, actions: Vec<TcpClientAction>) {
+        for action in actions {
+            match action {
+                TcpClientAction::CloseConnection(reason) => {
+                    self.handle_disconnect(reason);
+                }
+                TcpClientAction::HandleMessage(message) => {
+                    self.events.push(ClientEvent::Message(message));
+                }
+                TcpClientAction::HandleBinaryMessage(data) => {
+                    self.events.push(ClientEvent::Binary(data));
+                }
+                TcpClientAction::HandleClose(code, reason) => {
+                    self.events.push(ClientEvent::Closed(code, reason));
+                }
+                TcpClientAction::SendData(data) => {
+                    self.write_buffer.extend_from_slice(&data);
+                }
+                TcpClientAction::RequestWebSocketUpgrade(request) => {
+                    debug!(
+                        "[TCP Client Handler] ({0}) Received request from client to upgrade to WebSocket connection.",
+                        self.address
+                    );
+                    self.events.push(ClientEvent::UpgradeRequested(request));
+                }
+                TcpClientAction::UpgradeToWebSocket(request) => {
+                    self.upgrade_to_websocket(request);
+                }
+                TcpClientAction::HandleHttpRequest(request) => {
+                    self.events.push(ClientEvent::HttpRequest(request));
+                }
+                TcpClientAction::HandleError(error) => {
+                    self.handle_error(error);
+                }
+            }
+        }
+    }
+
+    /**
+     * Switches the connection to WebSocket once the application has accepted the upgrade.
+     */
+    fn upgrade_to_websocket(
+        &mut self,
+        request: Box<HttpRequest>
+    ) {
+        debug!("[TCP Client Handler] ({0}) Upgraded to WebSocket connection.", self.address);
+
+        // Communicate to server that connection has upgraded to WebSocket
+        self.events.push(ClientEvent::Upgraded(request));
+
+        // Replace the request handler with a websocket handler
+        let websocket_handler = WebSocketClientRequestHandler::new(
+            self.address,
+            self.websocket_config.clone()
+        );
+        self.request_handler = Box::new(websocket_handler);
+    }
+}
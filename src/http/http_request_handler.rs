@@ -1,7 +1,40 @@
-use super::request;
-use super::tcp_client_handler::{TcpClientAction, TcpClientRequestHandler};
+use super::request::{HttpRequest, HttpRequestDecoder, ParseError};
+use super::response::HttpResponse;
+use super::tcp_client_handler::{DisconnectReason, TcpClientAction, TcpClientRequestHandler};
+use crate::error::Error;
 use log::{debug, warn};
+use std::collections::VecDeque;
 use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// HTTP connection settings.
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Largest request head (request line and headers) accepted. Larger heads are answered
+    /// with `431 Request Header Fields Too Large`.
+    pub max_head_size: usize,
+    /// Largest request body accepted. Larger bodies are answered with `413 Content Too Large`.
+    pub max_body_size: usize,
+    /// Longest a client may take to send a complete request once it has connected or its
+    /// previous request has been answered. Clients that take longer are disconnected (with
+    /// `408 Request Timeout` if they had started sending a request).
+    pub request_timeout: Duration,
+    /// Most pipelined requests held while an earlier one is being answered. A client that
+    /// sends more has the requests already held answered, and its connection closed after the
+    /// last of them.
+    pub max_queued_requests: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            max_head_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+            request_timeout: Duration::from_secs(30),
+            max_queued_requests: 32,
+        }
+    }
+}
 
 pub struct HttpClientRequestHandler {
     //pub stream: &std::net::TcpStream,
@@ -9,30 +42,200 @@ pub struct HttpClientRequestHandler {
      * IP address of connected client.
      */
     pub address: std::net::SocketAddr,
+    /**
+     * Whether requests to upgrade to a WebSocket are accepted.
+     */
+    pub websockets_enabled: bool,
+    /**
+     * Decoder holding any partially received request.
+     */
+    decoder: HttpRequestDecoder,
+    /**
+     * Requests received while an earlier one is still waiting for its response. Responses are
+     * sent in the order the requests arrived.
+     */
+    queued: VecDeque<HttpRequest>,
+    /**
+     * Most requests `queued` may hold.
+     */
+    max_queued_requests: usize,
+    /**
+     * Whether the client pipelined more requests than may be queued. Nothing more is read from
+     * it, and the connection closes once the queued requests are answered.
+     */
+    pipeline_full: bool,
+    /**
+     * The request the application is answering (if any).
+     */
+    in_flight: Option<InFlightRequest>,
+    /**
+     * How long the client has to send a complete request.
+     */
+    request_timeout: Duration,
+    /**
+     * When the handler started waiting for the client's next request.
+     */
+    waiting_since: Instant,
+}
+
+/// What the handler needs to know about a request to send its response.
+struct InFlightRequest {
+    /// HEAD requests are answered without the body.
+    is_head: bool,
+    /// Whether the connection closes once the request is answered.
+    close: bool,
+    /// The request, if it asks to upgrade to a WebSocket. A `101 Switching Protocols`
+    /// response accepts the upgrade; any other response refuses it.
+    upgrade: Option<Box<HttpRequest>>,
 }
 
 impl HttpClientRequestHandler {
+    pub fn new(address: std::net::SocketAddr, websockets_enabled: bool, config: &HttpConfig) -> HttpClientRequestHandler {
+        HttpClientRequestHandler {
+            address,
+            websockets_enabled,
+            decoder: HttpRequestDecoder::new(config.max_head_size, config.max_body_size),
+            queued: VecDeque::new(),
+            max_queued_requests: config.max_queued_requests,
+            pipeline_full: false,
+            in_flight: None,
+            request_timeout: config.request_timeout,
+            waiting_since: Instant::now(),
+        }
+    }
+
     /**
-     * Handles an HTTP client request.
+     * Returns when the client runs out of time to send its next request, unless the
+     * application is answering one.
      */
-    fn handle_http_request(self: &HttpClientRequestHandler, mut stream: &std::net::TcpStream) {
-        // Just respond with 200 OK
-        let resp = b"HTTP/1.1 200 OK";
-        match stream.write(resp) {
-            Ok(_) => {
-                debug!(
-                    "[HTTP Client] ({0}) Sent response HTTP 200 OK",
-                    self.address
+    fn request_deadline(&self) -> Option<Instant> {
+        if self.in_flight.is_some() {
+            return None;
+        }
+        Some(self.waiting_since + self.request_timeout)
+    }
+
+    /**
+     * Passes the next queued request on, unless the application is still answering one.
+     */
+    fn next_request(&mut self) -> Vec<TcpClientAction> {
+        if self.in_flight.is_some() {
+            return Vec::new();
+        }
+        let request = match self.queued.pop_front() {
+            Some(request) => request,
+            None => return Vec::new(),
+        };
+        // The last request a client that overfilled its pipeline gets answered
+        let is_last = self.pipeline_full && self.queued.is_empty();
+
+        // Is this a request to upgrade to a websocket? (The application decides whether to
+        // accept it, once the handshake is known to be valid.)
+        if self.websockets_enabled && request.is_websocket_upgrade() {
+            if let Err(response) = check_websocket_handshake(&request) {
+                warn!(
+                    "[HTTP Client] ({0}) Invalid WebSocket handshake. Answering {1}.",
+                    self.address, response.status
                 );
+                let reason = DisconnectReason::ClosedByServer(String::from("Invalid WebSocket handshake"));
+                return vec![
+                    TcpClientAction::SendData(response.with_header("Connection", "close").to_bytes()),
+                    TcpClientAction::CloseConnection(reason),
+                ];
             }
-            Err(error) => {
-                debug!(
-                    "[HTTP Client] ({0}) Error sending HTTP 200 OK response. {1}",
-                    self.address, error
-                );
+            let upgrade = Box::new(request);
+            self.in_flight = Some(InFlightRequest { is_head: false, close: is_last, upgrade: Some(upgrade.clone()) });
+            return vec![TcpClientAction::RequestWebSocketUpgrade(upgrade)];
+        }
+
+        // HTTP/1.1 connections stay open unless the client asks otherwise, HTTP/1.0 ones close
+        // unless it asks to keep them open
+        let close = is_last
+            || request.headers.has_token("Connection", "close")
+            || (request.protocol == "HTTP/1.0" && !request.headers.has_token("Connection", "keep-alive"));
+        self.in_flight = Some(InFlightRequest { is_head: request.verb == "HEAD", close, upgrade: None });
+        vec![TcpClientAction::HandleHttpRequest(Box::new(request))]
+    }
+
+    /**
+     * Stops reading from a client that has pipelined more requests than may be queued. The
+     * requests beyond the limit are dropped and the connection closes after answering the rest.
+     */
+    fn limit_queue(&mut self) {
+        if self.queued.len() <= self.max_queued_requests {
+            return;
+        }
+        warn!(
+            "[HTTP Client] ({0}) Client pipelined more than {1} requests. Closing the connection after answering them.",
+            self.address, self.max_queued_requests
+        );
+        self.queued.truncate(self.max_queued_requests);
+        self.pipeline_full = true;
+        if self.queued.is_empty() {
+            if let Some(request) = self.in_flight.as_mut() {
+                request.close = true;
             }
         }
     }
+
+    /**
+     * Tells a client waiting to send a request's body to go ahead (`100 Continue`), once every
+     * earlier request has been answered.
+     */
+    fn continue_body(&mut self) -> Option<TcpClientAction> {
+        if self.in_flight.is_some() || !self.queued.is_empty() || self.pipeline_full || !self.decoder.take_continue() {
+            return None;
+        }
+        debug!("[HTTP Client] ({0}) Asking for the request body.", self.address);
+        Some(TcpClientAction::SendData(HttpResponse::new(100).to_bytes()))
+    }
+
+    /**
+     * Answers a malformed request with the matching error response and drops the client.
+     */
+    fn reject(&mut self, error: ParseError) -> Vec<TcpClientAction> {
+        warn!(
+            "[HTTP Client] ({0}) Error parsing client request: {1}",
+            self.address, error
+        );
+        let status = match error {
+            ParseError::HeadTooLarge => 431,
+            ParseError::BodyTooLarge => 413,
+            ParseError::UnsupportedTransferEncoding => 501,
+            ParseError::UnsupportedVersion(_) => 505,
+            _ => 400,
+        };
+        let response = HttpResponse::new(status).with_header("Connection", "close");
+        vec![
+            TcpClientAction::SendData(response.to_bytes()),
+            TcpClientAction::HandleError(Error::Http(error)),
+        ]
+    }
+}
+
+/// The error for a message sent to a client that has not upgraded to a WebSocket.
+fn not_websocket() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, "Messages can only be sent to WebSocket clients")
+}
+
+/**
+ * Checks that a request to upgrade to a WebSocket is a valid opening handshake (RFC 6455
+ * §4.2.1): a `GET` with a `Sec-WebSocket-Key` of 16 base64-encoded bytes, for version 13 of the
+ * protocol. Returns the response refusing it if not.
+ */
+fn check_websocket_handshake(request: &HttpRequest) -> Result<(), HttpResponse> {
+    if request.verb != "GET" {
+        return Err(HttpResponse::new(400));
+    }
+    let key = request.headers.get("Sec-WebSocket-Key").and_then(|key| base64::decode(key).ok());
+    if key.map(|key| key.len()) != Some(16) {
+        return Err(HttpResponse::new(400));
+    }
+    // Tell the client which version is supported
+    if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(HttpResponse::new(426).with_header("Sec-WebSocket-Version", "13"));
+    }
+    Ok(())
 }
 
 impl TcpClientRequestHandler for HttpClientRequestHandler {
@@ -40,43 +243,192 @@ impl TcpClientRequestHandler for HttpClientRequestHandler {
      * Handles an HTTP client request.
      */
     fn handle_request(
-        self: &HttpClientRequestHandler,
-        stream: &std::net::TcpStream,
+        &mut self,
+        _stream: &mut dyn Write,
         data: &[u8],
         num_bytes: &usize,
-    ) -> TcpClientAction {
+    ) -> Vec<TcpClientAction> {
         debug!(
             "[HTTP Client] ({0}) Received {1} bytes.",
             &self.address, num_bytes
         );
+        if self.pipeline_full {
+            debug!("[HTTP Client] ({0}) Ignoring bytes sent after a full pipeline.", &self.address);
+            return Vec::new();
+        }
 
-        match std::str::from_utf8(&data[0..*num_bytes]) {
-            Ok(payload) => {
-                // Parse the http request
-                let request = request::parse_http_request(payload);
-
-                // Is this a request to upgrade to a websocket?
-                if request.connection == "Upgrade" && request.upgrade == "websocket" {
-                    return TcpClientAction::UpgradeToWebSocket(request.sec_websocket_key);
-                } else {
-                    self.handle_http_request(stream);
-                }
-            }
-            Err(error) => {
-                warn!(
-                    "[HTTP Client] ({0}) Error parsing client request to UTF-8: {1}",
-                    self.address, error
-                );
+        // Parse the http requests that have fully arrived
+        match self.decoder.decode(&data[0..*num_bytes]) {
+            Ok(requests) => self.queued.extend(requests),
+            Err(error) => return self.reject(error),
+        };
+
+        let mut actions = self.next_request();
+        self.limit_queue();
+        actions.extend(self.continue_body());
+        actions
+    }
+
+    /**
+     * Sends the response to the request the application is answering, then passes the next
+     * request on.
+     */
+    fn send_http_response(
+        &mut self,
+        stream: &mut dyn Write,
+        mut response: HttpResponse,
+    ) -> Vec<TcpClientAction> {
+        let request = match self.in_flight.take() {
+            Some(request) => request,
+            None => {
+                warn!("[HTTP Client] ({0}) Dropping response {1}: no request is waiting for one.", self.address, response.status);
+                return Vec::new();
             }
+        };
+
+        let close = request.close || response.headers.has_token("Connection", "close");
+        if close && !response.headers.has_token("Connection", "close") {
+            response.headers.append("Connection", "close");
+        }
+        let bytes = if request.is_head { response.to_head_bytes() } else { response.to_bytes() };
+        if let Err(error) = stream.write_all(&bytes) {
+            return vec![TcpClientAction::HandleError(Error::Io(error))];
+        }
+        debug!("[HTTP Client] ({0}) Sent response {1} {2}", self.address, response.status, response.reason);
+        self.waiting_since = Instant::now();
+
+        if close {
+            return vec![TcpClientAction::CloseConnection(DisconnectReason::ClosedByServer(String::from("Connection: close")))];
+        }
+        if let Some(upgrade) = request.upgrade.filter(|_| response.status == 101) {
+            // Anything sent after the upgrade request belongs to the WebSocket
+            self.queued.clear();
+            return vec![TcpClientAction::UpgradeToWebSocket(upgrade)];
         }
+        let mut actions = self.next_request();
+        actions.extend(self.continue_body());
+        actions
+    }
 
-        return TcpClientAction::None;
+    /**
+     * Disconnects the client if it has taken too long to send its next request.
+     */
+    fn handle_tick(&mut self) -> Vec<TcpClientAction> {
+        match self.request_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return Vec::new(),
+        }
+
+        let reason = DisconnectReason::TimedOut;
+        if self.decoder.is_empty() {
+            debug!("[HTTP Client] ({0}) Connection was idle too long.", self.address);
+            return vec![TcpClientAction::CloseConnection(reason)];
+        }
+        warn!("[HTTP Client] ({0}) Client took too long to send its request.", self.address);
+        let response = HttpResponse::new(408).with_header("Connection", "close");
+        vec![TcpClientAction::SendData(response.to_bytes()), TcpClientAction::CloseConnection(reason)]
     }
 
+    fn next_tick(&self) -> Option<Instant> {
+        self.request_deadline()
+    }
+
+    /**
+     * Refuses to send a message: it would end up in the middle of the HTTP responses.
+     */
     fn send_response(
-        self: &HttpClientRequestHandler, 
-        stream: &mut std::net::TcpStream,
-        message: String) {
-        stream.write(message.to_string().as_bytes()).expect("Error sending message to client.");
+        &self, 
+        _stream: &mut dyn Write,
+        _message: &str) -> std::io::Result<()> {
+        Err(not_websocket())
+    }
+
+    /**
+     * Refuses to send a binary message, as for `send_response`.
+     */
+    fn send_binary_response(
+        &self,
+        _stream: &mut dyn Write,
+        _data: Vec<u8>) -> std::io::Result<()> {
+        Err(not_websocket())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(max_queued_requests: usize) -> HttpClientRequestHandler {
+        let config = HttpConfig { max_queued_requests, ..HttpConfig::default() };
+        HttpClientRequestHandler::new("127.0.0.1:4000".parse().unwrap(), true, &config)
+    }
+
+    /// Feeds bytes to the handler and returns how many requests it passed on.
+    fn receive(handler: &mut HttpClientRequestHandler, data: &[u8]) -> usize {
+        handler
+            .handle_request(&mut Vec::new(), data, &data.len())
+            .iter()
+            .filter(|action| matches!(action, TcpClientAction::HandleHttpRequest(_)))
+            .count()
+    }
+
+    /// Answers the request in flight, returning what was sent, the number of requests passed
+    /// on next and whether the connection is closing.
+    fn answer(handler: &mut HttpClientRequestHandler) -> (String, usize, bool) {
+        let mut stream: Vec<u8> = Vec::new();
+        let actions = handler.send_http_response(&mut stream, HttpResponse::no_content());
+        let passed_on = actions
+            .iter()
+            .filter(|action| matches!(action, TcpClientAction::HandleHttpRequest(_)))
+            .count();
+        let closing = actions.iter().any(|action| matches!(action, TcpClientAction::CloseConnection(_)));
+        (String::from_utf8(stream).unwrap(), passed_on, closing)
+    }
+
+    fn pipelined(count: usize) -> Vec<u8> {
+        "GET / HTTP/1.1\r\nHost: a\r\n\r\n".repeat(count).into_bytes()
+    }
+
+    #[test]
+    fn answers_pipelined_requests_one_at_a_time() {
+        let mut handler = handler(4);
+        assert_eq!(receive(&mut handler, &pipelined(3)), 1);
+        for remaining in (0..3).rev() {
+            let (sent, passed_on, closing) = answer(&mut handler);
+            assert!(sent.starts_with("HTTP/1.1 204 No Content\r\n"));
+            assert!(!sent.contains("Connection: close"));
+            assert_eq!(passed_on, usize::from(remaining > 0));
+            assert!(!closing);
+        }
+    }
+
+    #[test]
+    fn closes_connections_that_pipeline_too_many_requests() {
+        let mut handler = handler(4);
+        assert_eq!(receive(&mut handler, &pipelined(20)), 1);
+        // Nothing more is read
+        assert_eq!(receive(&mut handler, &pipelined(1)), 0);
+
+        // The request in flight and the four queued ones are answered, the last one closing
+        // the connection
+        for _ in 0..4 {
+            let (sent, passed_on, closing) = answer(&mut handler);
+            assert!(!sent.contains("Connection: close"));
+            assert_eq!((passed_on, closing), (1, false));
+        }
+        let (sent, passed_on, closing) = answer(&mut handler);
+        assert!(sent.contains("\r\nConnection: close\r\n"), "{}", sent);
+        assert_eq!((passed_on, closing), (0, true));
+    }
+
+    #[test]
+    fn closes_after_the_request_in_flight_when_nothing_may_be_queued() {
+        let mut handler = handler(0);
+        assert_eq!(receive(&mut handler, &pipelined(1)), 1);
+        assert_eq!(receive(&mut handler, &pipelined(1)), 0);
+
+        let (sent, passed_on, closing) = answer(&mut handler);
+        assert!(sent.contains("\r\nConnection: close\r\n"), "{}", sent);
+        assert_eq!((passed_on, closing), (0, true));
     }
 }
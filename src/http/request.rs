@@ -1,92 +1,917 @@
+use std::fmt;
+
+/// A parsed HTTP request head (request line and headers).
+#[derive(Clone, Debug, PartialEq)]
 pub struct HttpRequest {
+    /// The request method, e.g. `GET`.
     pub verb: String,
+    /// The request target exactly as sent, e.g. `/files/a%20b?sort=name`.
+    pub target: String,
+    /// The percent-decoded path of the target, without the query, e.g. `/files/a b`.
     pub path: String,
+    /// The query of the target (without the `?`), still percent-encoded.
+    pub query: Option<String>,
+    /// The HTTP version, e.g. `HTTP/1.1`.
     pub protocol: String,
-    pub host: String,
-    pub connection: String,
-    pub cache_control: String,
-    pub user_agent: String,
-    pub accept: String,
-    pub accept_encoding: String,
-    pub accept_language: String,
-    pub sec_websocket_version: String,
-    pub sec_websocket_key: String,
-    pub upgrade: String,
-    pub sec_websocket_extensions: String
-}
-
-pub fn parse_http_request(request: &str) -> HttpRequest {
-    let request_parts: Vec<&str> = request.split("\n").collect();
-    
-    // Parse verb and version
-    let vpv: Vec<&str> = request_parts[0].split(" ").collect();
-
-    let mut host: String = String::from("");
-    let mut connection: String = String::from("");
-    let mut cache_control: String = String::from("");
-    let mut user_agent: String = String::from("");
-    let mut accept: String = String::from("");
-    let mut accept_encoding: String = String::from("");
-    let mut accept_language: String = String::from("");
-    let mut sec_websocket_version: String = String::from("");
-    let mut sec_websocket_key: String = String::from("");
-    let mut upgrade: String = String::from("");
-    let mut sec_websocket_extensions: String = String::from("");
-
-    for (_, x) in request_parts.iter().enumerate() {
-        let parts: Vec<&str> = x.split(":").collect();
-        if parts[0] == "Host" {
-            host = String::from(parts[1].trim());
-        }
-        if parts[0] == "Connection" {
-            connection = String::from(parts[1].trim());
-        }
-        if parts[0] == "Cache-Control" {
-            cache_control = String::from(parts[1].trim());
-        }
-        if parts[0] == "User-Agent" {
-            user_agent = String::from(parts[1].trim());
-        }
-        if parts[0] == "Accept" {
-            accept = String::from(parts[1].trim());
-        }
-        if parts[0] == "Accept-Encoding" {
-            accept_encoding = String::from(parts[1].trim());
-        }
-        if parts[0] == "Accept-Language" {
-            accept_language = String::from(parts[1].trim());
-        }
-        if parts[0] == "Sec-WebSocket-Version" {
-            sec_websocket_version = String::from(parts[1].trim());
-        }
-        if parts[0] == "Sec-WebSocket-Key" {
-            sec_websocket_key = String::from(parts[1].trim());
-        }
-        if parts[0] == "Upgrade" {
-            upgrade = String::from(parts[1].trim());
-        }
-        if parts[0] == "Sec-WebSocket-Extensions" {
-            sec_websocket_extensions = String::from(parts[1].trim());
-        }
-    }
-
-    // Start building http request
-    let parsed: HttpRequest = HttpRequest {
-        verb: String::from(vpv[0].trim()),
-        path: String::from(vpv[1].trim()),
-        protocol: String::from(vpv[2].trim()),
-        host: host,
-        connection: connection,
-        cache_control: cache_control,
-        user_agent: user_agent,
-        accept: accept,
-        accept_encoding: accept_encoding,
-        accept_language: accept_language,
-        sec_websocket_version: sec_websocket_version,
-        sec_websocket_key: sec_websocket_key,
-        upgrade: upgrade,
-        sec_websocket_extensions: sec_websocket_extensions
+    pub headers: Headers,
+    /// The request's body (empty if it has none), with any transfer coding removed.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the decoded name/value pairs of the query, in the order they were sent (see
+    /// `parse_query`).
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        self.query.as_deref().map_or_else(Vec::new, parse_query)
+    }
+
+    /// Returns the path of the target as sent (still percent-encoded), without the query, e.g.
+    /// `/files/a%20b`. Unlike `path`, an encoded `/` (`%2F`) can be told apart from a segment
+    /// separator.
+    pub fn raw_path(&self) -> &str {
+        split_target(&self.verb, &self.target).map_or(&self.path, |(path, _)| path)
+    }
+
+    /// Whether the request asks to upgrade the connection to a WebSocket.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.headers.has_token("Connection", "upgrade") && self.headers.has_token("Upgrade", "websocket")
+    }
+}
+
+/**
+ * The header fields of a request, in the order they were sent.
+ *
+ * Names are matched case-insensitively and a name can have several values (e.g. when a header
+ * is repeated).
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Adds a value for a header, keeping any values it already has.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((String::from(name), String::from(value)));
+    }
+
+    /// Replaces every value of a header with the given one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Removes every value of a header.
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    /// Returns the first value of a header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of a header, in the order they were sent.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a header has a value.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether a comma-separated list header (e.g. `Connection`) has the given element. The
+    /// comparison ignores case.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|element| element.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Returns every header name and value, in the order they were sent.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Ways an HTTP request head can be malformed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// The request line is not `method SP request-target SP HTTP-version`.
+    InvalidRequestLine,
+    /// The method is not a token.
+    InvalidMethod,
+    /// The request target is not in a form the server accepts, or is not percent-encoded
+    /// properly.
+    InvalidTarget,
+    /// The request is for an HTTP version other than 1.0 or 1.1.
+    UnsupportedVersion(String),
+    /// A header line is not `field-name ":" field-value`.
+    InvalidHeader,
+    /// An HTTP/1.1 request has no Host header, or has more than one.
+    InvalidHost,
+    /// The request head is larger than the server accepts.
+    HeadTooLarge,
+    /// A Content-Length header is not a number, or several of them disagree.
+    InvalidContentLength,
+    /// The Transfer-Encoding header does not end in `chunked`, or the request also has a
+    /// Content-Length or is an HTTP/1.0 request.
+    InvalidTransferEncoding,
+    /// The body has a transfer coding other than `chunked`.
+    UnsupportedTransferEncoding,
+    /// A chunk of a chunked body is malformed.
+    InvalidChunk,
+    /// The body is larger than the server accepts.
+    BodyTooLarge,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidRequestLine => write!(f, "Malformed HTTP request line"),
+            ParseError::InvalidMethod => write!(f, "Invalid HTTP method"),
+            ParseError::InvalidTarget => write!(f, "Invalid request target"),
+            ParseError::UnsupportedVersion(version) => write!(f, "Unsupported HTTP version: {}", version),
+            ParseError::InvalidHeader => write!(f, "Malformed header field"),
+            ParseError::InvalidHost => write!(f, "An HTTP/1.1 request needs exactly one Host header"),
+            ParseError::HeadTooLarge => write!(f, "Request head too large"),
+            ParseError::InvalidContentLength => write!(f, "Invalid Content-Length"),
+            ParseError::InvalidTransferEncoding => write!(f, "Invalid Transfer-Encoding"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "Unsupported transfer coding"),
+            ParseError::InvalidChunk => write!(f, "Malformed chunk in chunked body"),
+            ParseError::BodyTooLarge => write!(f, "Request body too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How the end of a request's body is found.
+enum BodyFraming {
+    /// This many more bytes of the body are to come.
+    Length(usize),
+    /// The body is chunked and the next chunk starts here.
+    Chunked,
+    /// The last chunk has been read and the trailer section (ended by an empty line) follows.
+    Trailers,
+}
+
+/**
+ * Incrementally decodes HTTP requests, bodies included, from a TCP byte stream.
+ *
+ * Bytes are accumulated across reads, so requests split over several reads, or several requests
+ * arriving in a single read, are decoded correctly.
+ */
+pub struct HttpRequestDecoder {
+    buffer: Vec<u8>,
+    max_head_size: usize,
+    max_body_size: usize,
+    /// A request whose head has been decoded but whose body has not fully arrived.
+    pending: Option<(HttpRequest, BodyFraming)>,
+    /// Whether the client of the pending request is waiting for `100 Continue` before it sends
+    /// the body.
+    awaiting_continue: bool,
+}
+
+impl HttpRequestDecoder {
+    /// Creates a decoder that rejects request heads larger than `max_head_size` and bodies
+    /// larger than `max_body_size`.
+    pub fn new(max_head_size: usize, max_body_size: usize) -> HttpRequestDecoder {
+        HttpRequestDecoder {
+            buffer: Vec::new(),
+            max_head_size,
+            max_body_size,
+            pending: None,
+            awaiting_continue: false,
+        }
+    }
+
+    /// Whether no part of a request is waiting for the rest of it to arrive.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.pending.is_none()
+    }
+
+    /// Returns whether the client is waiting for `100 Continue` before sending the body of the
+    /// request being decoded. Only returns `true` once for each request.
+    pub fn take_continue(&mut self) -> bool {
+        std::mem::take(&mut self.awaiting_continue)
+    }
+
+    /// Appends bytes read from the stream and returns every request that is now complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes read from the stream.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<HttpRequest>, ParseError> {
+        self.buffer.extend_from_slice(data);
+
+        let mut requests: Vec<HttpRequest> = Vec::new();
+        let mut consumed: usize = 0;
+        loop {
+            let (request, framing) = match self.pending.take() {
+                Some(pending) => pending,
+                None => match parse_http_request(&self.buffer[consumed..])? {
+                    Some((_, head_length)) if head_length > self.max_head_size => {
+                        return Err(ParseError::HeadTooLarge);
+                    }
+                    Some((request, head_length)) => {
+                        consumed += head_length;
+                        let framing = body_framing(&request, self.max_body_size)?;
+                        self.awaiting_continue = expects_continue(&request, &framing);
+                        (request, framing)
+                    }
+                    None if self.buffer.len() - consumed > self.max_head_size => {
+                        return Err(ParseError::HeadTooLarge);
+                    }
+                    None => break,
+                },
+            };
+
+            match self.read_body(request, framing, &mut consumed)? {
+                Some(request) => {
+                    self.awaiting_continue = false;
+                    requests.push(request);
+                }
+                None => break,
+            }
+        }
+
+        self.buffer.drain(..consumed);
+        Ok(requests)
+    }
+
+    /**
+     * Reads as much of a request's body as has arrived. Returns the request once its body is
+     * complete; otherwise keeps it until more of the body arrives.
+     */
+    fn read_body(
+        &mut self,
+        mut request: HttpRequest,
+        mut framing: BodyFraming,
+        consumed: &mut usize,
+    ) -> Result<Option<HttpRequest>, ParseError> {
+        loop {
+            let available = &self.buffer[*consumed..];
+            match framing {
+                BodyFraming::Length(remaining) => {
+                    let length = remaining.min(available.len());
+                    request.body.extend_from_slice(&available[..length]);
+                    *consumed += length;
+                    if length < remaining {
+                        self.pending = Some((request, BodyFraming::Length(remaining - length)));
+                        return Ok(None);
+                    }
+                    return Ok(Some(request));
+                }
+                BodyFraming::Chunked => {
+                    let (line, line_length) = match next_line(available, self.max_head_size)? {
+                        Some(line) => line,
+                        None => break,
+                    };
+                    let size = parse_chunk_size(line)?;
+                    if size == 0 {
+                        *consumed += line_length;
+                        framing = BodyFraming::Trailers;
+                        continue;
+                    }
+                    if size > self.max_body_size - request.body.len() {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+
+                    // The chunk's data is followed by a line break
+                    let data = &available[line_length..];
+                    if data.len() < size {
+                        break;
+                    }
+                    let terminator = match &data[size..] {
+                        [b'\r', b'\n', ..] => 2,
+                        [b'\n', ..] => 1,
+                        [] | [b'\r'] => break,
+                        _ => return Err(ParseError::InvalidChunk),
+                    };
+                    request.body.extend_from_slice(&data[..size]);
+                    *consumed += line_length + size + terminator;
+                }
+                BodyFraming::Trailers => {
+                    // Trailer fields are not used, so they are skipped
+                    let (line, line_length) = match next_line(available, self.max_head_size)? {
+                        Some(line) => line,
+                        None => break,
+                    };
+                    *consumed += line_length;
+                    if line.is_empty() {
+                        return Ok(Some(request));
+                    }
+                }
+            }
+        }
+
+        self.pending = Some((request, framing));
+        Ok(None)
+    }
+}
+
+/**
+ * Works out how a request's body is delimited (RFC 9112 section 6.3).
+ */
+fn body_framing(request: &HttpRequest, max_body_size: usize) -> Result<BodyFraming, ParseError> {
+    if request.headers.contains("Transfer-Encoding") {
+        if request.headers.contains("Content-Length") || request.protocol == "HTTP/1.0" {
+            return Err(ParseError::InvalidTransferEncoding);
+        }
+
+        // Chunked must be the last coding, and is the only one understood
+        let codings: Vec<&str> = request
+            .headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        return match codings.split_last() {
+            Some((last, [])) if last.eq_ignore_ascii_case("chunked") => Ok(BodyFraming::Chunked),
+            Some((last, _)) if last.eq_ignore_ascii_case("chunked") => Err(ParseError::UnsupportedTransferEncoding),
+            _ => Err(ParseError::InvalidTransferEncoding),
+        };
+    }
+
+    // Repeated Content-Length values must all be the same
+    let mut length: Option<usize> = None;
+    for value in request.headers.get_all("Content-Length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let value: usize = value.parse().map_err(|_| ParseError::BodyTooLarge)?;
+        if length.is_some_and(|length| length != value) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        length = Some(value);
+    }
+
+    match length {
+        Some(length) if length > max_body_size => Err(ParseError::BodyTooLarge),
+        Some(length) => Ok(BodyFraming::Length(length)),
+        None => Ok(BodyFraming::Length(0)),
+    }
+}
+
+/// Whether a request asks the server to confirm it wants the body before it is sent (RFC 9110
+/// section 10.1.1). HTTP/1.0 clients can't ask.
+fn expects_continue(request: &HttpRequest, framing: &BodyFraming) -> bool {
+    request.protocol == "HTTP/1.1"
+        && request.headers.has_token("Expect", "100-continue")
+        && !matches!(framing, BodyFraming::Length(0))
+}
+
+/// Returns the next line (without its line break) and its length including the line break, or
+/// `None` if the line has not fully arrived. Fails if the line is longer than `max_length`.
+fn next_line(data: &[u8], max_length: usize) -> Result<Option<(&[u8], usize)>, ParseError> {
+    match data.iter().position(|&byte| byte == b'\n') {
+        Some(end) if end > max_length => Err(ParseError::InvalidChunk),
+        Some(end) => Ok(Some((strip_cr(&data[..end])?, end + 1))),
+        None if data.len() > max_length => Err(ParseError::InvalidChunk),
+        None => Ok(None),
+    }
+}
+
+/// Parses a chunk's size line (`chunk-size [ chunk-ext ]`). Chunk extensions are ignored.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    let size = match line.iter().position(|&byte| byte == b';') {
+        Some(end) => &line[..end],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .map_err(|_| ParseError::InvalidChunk)?
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidChunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| ParseError::BodyTooLarge)
+}
+
+/**
+ * Parses the head of an HTTP/1.x request (RFC 9112). The request's body is left empty; use an
+ * `HttpRequestDecoder` to read requests with their bodies.
+ *
+ * Returns `Ok(None)` if `data` does not yet hold the whole head. Otherwise returns the request
+ * and the length of its head in bytes, which is where the request's body (if any) starts.
+ *
+ * Lines may end in CRLF or a bare LF, empty lines before the request line are skipped and folded
+ * header values are unfolded (each fold becomes a space).
+ */
+pub fn parse_http_request(data: &[u8]) -> Result<Option<(HttpRequest, usize)>, ParseError> {
+    // Skip empty lines before the request line
+    let mut start = 0;
+    loop {
+        if data[start..].starts_with(b"\r\n") {
+            start += 2;
+        } else if data[start..].starts_with(b"\n") {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+
+    // Split the head into lines, stopping at the empty line that ends it
+    let mut lines: Vec<&[u8]> = Vec::new();
+    let mut position = start;
+    let head_length = loop {
+        let end = match data[position..].iter().position(|&byte| byte == b'\n') {
+            Some(offset) => position + offset,
+            None => return Ok(None),
+        };
+        let line = strip_cr(&data[position..end])?;
+        position = end + 1;
+        if line.is_empty() {
+            break position;
+        }
+        lines.push(line);
+    };
+
+    let (request_line, header_lines) = match lines.split_first() {
+        Some(split) => split,
+        None => return Err(ParseError::InvalidRequestLine),
+    };
+    let (verb, target, protocol) = parse_request_line(request_line)?;
+    let headers = parse_headers(header_lines)?;
+
+    // HTTP/1.1 requests must name exactly one host
+    if protocol == "HTTP/1.1" && headers.get_all("Host").count() != 1 {
+        return Err(ParseError::InvalidHost);
+    }
+
+    let (path, query) = parse_target(&verb, &target)?;
+    let request = HttpRequest { verb, target, path, query, protocol, headers, body: Vec::new() };
+    Ok(Some((request, head_length)))
+}
+
+/// Removes the CR from the end of a line. Fails if a CR appears anywhere else.
+fn strip_cr(line: &[u8]) -> Result<&[u8], ParseError> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.contains(&b'\r') {
+        return Err(ParseError::InvalidHeader);
+    }
+    Ok(line)
+}
+
+/// Splits the request line into its method, target and version.
+fn parse_request_line(line: &[u8]) -> Result<(String, String, String), ParseError> {
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidRequestLine)?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() != 3 {
+        return Err(ParseError::InvalidRequestLine);
+    }
+    let (verb, target, protocol) = (parts[0], parts[1], parts[2]);
+
+    if !is_token(verb) {
+        return Err(ParseError::InvalidMethod);
+    }
+    if target.is_empty() || !target.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(ParseError::InvalidTarget);
+    }
+    match protocol {
+        "HTTP/1.1" | "HTTP/1.0" => {}
+        _ if is_http_version(protocol) => return Err(ParseError::UnsupportedVersion(String::from(protocol))),
+        _ => return Err(ParseError::InvalidRequestLine),
+    }
+
+    Ok((String::from(verb), String::from(target), String::from(protocol)))
+}
+
+/// Whether a version has the form `HTTP/DIGIT.DIGIT`.
+fn is_http_version(version: &str) -> bool {
+    match version.strip_prefix("HTTP/").map(str::as_bytes) {
+        Some([major, b'.', minor]) => major.is_ascii_digit() && minor.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+/// Parses the header lines, unfolding values continued on the next line.
+fn parse_headers(lines: &[&[u8]]) -> Result<Headers, ParseError> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in lines {
+        let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidHeader)?;
+        if line.bytes().any(|byte| byte.is_ascii_control() && byte != b'\t') {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        // A line starting with whitespace continues the previous value (obsolete line folding)
+        if line.starts_with([' ', '\t']) {
+            let (_, value) = fields.last_mut().ok_or(ParseError::InvalidHeader)?;
+            let continuation = line.trim_matches([' ', '\t']);
+            if !continuation.is_empty() {
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+                value.push_str(continuation);
+            }
+            continue;
+        }
+
+        // No whitespace is allowed between the name and the colon
+        let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+        if !is_token(name) {
+            return Err(ParseError::InvalidHeader);
+        }
+        fields.push((String::from(name), String::from(value.trim_matches([' ', '\t']))));
+    }
+    Ok(Headers { fields })
+}
+
+/// Splits a request target into its decoded path and its query.
+fn parse_target(verb: &str, target: &str) -> Result<(String, Option<String>), ParseError> {
+    let (path, query) = split_target(verb, target)?;
+
+    // The authority form is not percent-encoded
+    let path = if verb == "CONNECT" {
+        String::from(path)
+    } else {
+        percent_decode(path).ok_or(ParseError::InvalidTarget)?
+    };
+    Ok((path, query.map(String::from)))
+}
+
+/// Splits a request target into its path (still percent-encoded) and its query.
+fn split_target<'a>(verb: &str, target: &'a str) -> Result<(&'a str, Option<&'a str>), ParseError> {
+    // Asterisk form (`OPTIONS * HTTP/1.1`)
+    if target == "*" {
+        return if verb == "OPTIONS" {
+            Ok((target, None))
+        } else {
+            Err(ParseError::InvalidTarget)
+        };
+    }
+
+    // Authority form (`CONNECT host:port HTTP/1.1`)
+    if verb == "CONNECT" {
+        return Ok((target, None));
+    }
+
+    // Absolute form (`GET http://host/path HTTP/1.1`) carries the path after the authority
+    let target = target.split_once('#').map_or(target, |(target, _)| target);
+    let origin = if target.starts_with('/') {
+        target
+    } else {
+        let (_, rest) = target.split_once("://").ok_or(ParseError::InvalidTarget)?;
+        match rest.find(['/', '?']) {
+            Some(index) => &rest[index..],
+            None => "",
+        }
+    };
+
+    let (path, query) = match origin.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (origin, None),
     };
+    let path = if path.is_empty() { "/" } else { path };
+    Ok((path, query))
+}
+
+/// Decodes `%XX` escapes. Fails if an escape is malformed or the result is not UTF-8.
+pub fn percent_decode(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = encoded.get(index + 1..index + 3)?;
+            if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/**
+ * Parses a query string (`a=1&b=two+words`) into decoded name/value pairs.
+ *
+ * `+` is decoded as a space, names without a value (`flag`) get an empty one and pairs that
+ * don't decode are skipped.
+ */
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = percent_decode(&name.replace('+', " "))?;
+            let value = percent_decode(&value.replace('+', " "))?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Whether a string is an RFC 9110 token (as used for methods and header names).
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a complete request head, failing the test if it has not fully arrived.
+    fn parse(head: &str) -> Result<HttpRequest, ParseError> {
+        parse_http_request(head.as_bytes()).map(|parsed| parsed.expect("incomplete head").0)
+    }
+
+    #[test]
+    fn parses_a_request_head() {
+        let head = "GET /files/a%20b?sort=name HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\nbody";
+        let (request, head_length) = parse_http_request(head.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.verb, "GET");
+        assert_eq!(request.target, "/files/a%20b?sort=name");
+        assert_eq!(request.path, "/files/a b");
+        assert_eq!(request.raw_path(), "/files/a%20b");
+        assert_eq!(request.query.as_deref(), Some("sort=name"));
+        assert_eq!(request.protocol, "HTTP/1.1");
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request.headers.len(), 2);
+        assert_eq!(head_length, head.len() - 4);
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_head() {
+        let head = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        for end in 0..head.len() {
+            assert_eq!(parse_http_request(&head.as_bytes()[..end]), Ok(None), "head cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds_and_leading_empty_lines() {
+        let request = parse("\r\n\nGET / HTTP/1.1\nHost: example.com\n\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.headers.get("Host"), Some("example.com"));
+    }
+
+    #[test]
+    fn unfolds_folded_header_values() {
+        let request = parse("GET / HTTP/1.1\r\nHost: example.com\r\nX-Long: one\r\n  two\r\n\tthree \r\n\r\n").unwrap();
+        assert_eq!(request.headers.get("X-Long"), Some("one two three"));
+
+        // A fold can't start the header section
+        assert_eq!(parse("GET / HTTP/1.1\r\n folded\r\nHost: example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+    }
+
+    #[test]
+    fn keeps_repeated_headers_in_order() {
+        let request = parse("GET / HTTP/1.1\r\nHost: example.com\r\nCookie: a=1\r\ncookie: b=2\r\n\r\n").unwrap();
+        assert_eq!(request.headers.get_all("COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn requires_exactly_one_host_for_http_1_1() {
+        assert_eq!(parse("GET / HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidHost));
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n"),
+            Err(ParseError::InvalidHost)
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: a.example\r\nhost: a.example\r\n\r\n"),
+            Err(ParseError::InvalidHost)
+        );
+
+        // HTTP/1.0 predates Host
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn takes_the_path_from_absolute_form_targets() {
+        let request = parse("GET http://example.com/a%20b?x=1#top HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/a b");
+        assert_eq!(request.raw_path(), "/a%20b");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+
+        let request = parse("GET http://example.com HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.query, None);
+
+        let request = parse("GET http://example.com?x=1 HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+
+        // Neither origin nor absolute form
+        assert_eq!(parse("GET example.com/a HTTP/1.1\r\nHost: example.com\r\n\r\n"), Err(ParseError::InvalidTarget));
+    }
+
+    #[test]
+    fn accepts_the_asterisk_form_for_options_only() {
+        let request = parse("OPTIONS * HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.path, "*");
+        assert_eq!(request.query, None);
+
+        assert_eq!(parse("GET * HTTP/1.1\r\nHost: example.com\r\n\r\n"), Err(ParseError::InvalidTarget));
+    }
+
+    #[test]
+    fn keeps_authority_form_targets_as_sent() {
+        let request = parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+        assert_eq!(request.path, "example.com:443");
+        assert_eq!(request.raw_path(), "example.com:443");
+        assert_eq!(request.query, None);
+    }
+
+    #[test]
+    fn rejects_malformed_percent_escapes() {
+        for target in ["/a%", "/a%2", "/a%zz", "/a%+1", "/a%FF"] {
+            let head = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target);
+            assert_eq!(parse(&head), Err(ParseError::InvalidTarget), "target {}", target);
+        }
+        assert_eq!(percent_decode("%C3%A9t%c3%a9"), Some(String::from("été")));
+        assert_eq!(percent_decode("a%2Fb"), Some(String::from("a/b")));
+    }
+
+    #[test]
+    fn rejects_a_cr_in_the_middle_of_a_line() {
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: exa\rmple.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse("GET /\r HTTP/1.1\r\nHost: example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+    }
+
+    #[test]
+    fn rejects_whitespace_before_the_colon() {
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost : example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost\t: example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+    }
+
+    #[test]
+    fn rejects_malformed_header_lines() {
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse("GET / HTTP/1.1\r\n: example.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: exa\x01mple.com\r\n\r\n"), Err(ParseError::InvalidHeader));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert_eq!(parse("GET /  HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidRequestLine));
+        assert_eq!(parse("GET /\r\n\r\n"), Err(ParseError::InvalidRequestLine));
+        assert_eq!(parse("GET / FTP/1.1\r\n\r\n"), Err(ParseError::InvalidRequestLine));
+        assert_eq!(parse("G(T / HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidMethod));
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n"), Err(ParseError::UnsupportedVersion(String::from("HTTP/2.0"))));
+    }
+
+    #[test]
+    fn parses_queries() {
+        assert_eq!(
+            parse_query("a=1&b=two+words&c=%26%3D&flag&&d="),
+            [
+                (String::from("a"), String::from("1")),
+                (String::from("b"), String::from("two words")),
+                (String::from("c"), String::from("&=")),
+                (String::from("flag"), String::new()),
+                (String::from("d"), String::new()),
+            ]
+        );
+        assert_eq!(parse_query("x=1=2"), [(String::from("x"), String::from("1=2"))]);
+        assert_eq!(parse_query("a%2Bb=%2B"), [(String::from("a+b"), String::from("+"))]);
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn skips_query_pairs_that_do_not_decode() {
+        assert_eq!(parse_query("bad=%zz&good=1&%FF=x"), [(String::from("good"), String::from("1"))]);
+    }
 
-    return parsed;
-}
\ No newline at end of file
+    /// Decodes the given bytes with the default limits.
+    fn decode(data: &str) -> Result<Vec<HttpRequest>, ParseError> {
+        HttpRequestDecoder::new(8 * 1024, 1024).decode(data.as_bytes())
+    }
+
+    #[test]
+    fn reads_bodies_by_content_length() {
+        let requests = decode("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, b"hello");
+        assert!(requests[1].body.is_empty());
+    }
+
+    #[test]
+    fn accepts_repeated_content_lengths_that_agree() {
+        let requests = decode("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello").unwrap();
+        assert_eq!(requests[0].body, b"hello");
+    }
+
+    #[test]
+    fn rejects_conflicting_or_malformed_content_lengths() {
+        for lengths in ["Content-Length: 5\r\nContent-Length: 6", "Content-Length: 5, 6", "Content-Length: +5", "Content-Length: 0x5", "Content-Length:"] {
+            let head = format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n\r\nhello!", lengths);
+            assert_eq!(decode(&head), Err(ParseError::InvalidContentLength), "{}", lengths);
+        }
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_with_content_length() {
+        let head = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(decode(head), Err(ParseError::InvalidTransferEncoding));
+    }
+
+    #[test]
+    fn rejects_transfer_codings_other_than_a_final_chunked() {
+        let request = |codings: &str| format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n", codings);
+        assert_eq!(decode(&request("gzip, chunked")), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(decode(&request("chunked, gzip")), Err(ParseError::InvalidTransferEncoding));
+        assert_eq!(decode(&request("gzip")), Err(ParseError::InvalidTransferEncoding));
+        assert_eq!(
+            decode("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"),
+            Err(ParseError::InvalidTransferEncoding)
+        );
+        assert_eq!(decode(&request("Chunked")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reads_chunked_bodies_and_skips_trailers() {
+        let requests = decode(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;name=value\r\nhello\r\n1\r\n \r\nA\r\n0123456789\r\n0\r\n\
+             Checksum: abc\r\nExpires: never\r\n\r\n\
+             GET / HTTP/1.1\r\nHost: a\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, b"hello 0123456789");
+        // Trailer fields are not added to the headers
+        assert!(!requests[0].headers.contains("Checksum"));
+        assert_eq!(requests[1].verb, "GET");
+    }
+
+    #[test]
+    fn reads_chunked_bodies_split_at_every_byte() {
+        let request = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX: y\r\n\r\n";
+        for split in 1..request.len() {
+            let mut decoder = HttpRequestDecoder::new(8 * 1024, 1024);
+            assert!(decoder.decode(&request.as_bytes()[..split]).unwrap().is_empty(), "split at {}", split);
+            let requests = decoder.decode(&request.as_bytes()[split..]).unwrap();
+            assert_eq!(requests.len(), 1, "split at {}", split);
+            assert_eq!(requests[0].body, b"hello", "split at {}", split);
+            assert!(decoder.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_chunks() {
+        let request = |size: &str| format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\n", size);
+        // Over the body limit (1 KiB), even before the chunk's data arrives
+        assert_eq!(decode(&request("401")), Err(ParseError::BodyTooLarge));
+        assert_eq!(decode(&request("FFFFFFFFFFFFFFFFFFFF")), Err(ParseError::BodyTooLarge));
+        // The limit covers the whole body, not each chunk
+        let body = format!("{0}{1}\r\n201\r\n", request("200"), "x".repeat(0x200));
+        assert_eq!(decode(&body), Err(ParseError::BodyTooLarge));
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let request = |chunks: &str| format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}", chunks);
+        assert_eq!(decode(&request("5\r\nhelloX\r\n")), Err(ParseError::InvalidChunk));
+        assert_eq!(decode(&request("-5\r\n")), Err(ParseError::InvalidChunk));
+        assert_eq!(decode(&request(";ext\r\n")), Err(ParseError::InvalidChunk));
+    }
+
+    #[test]
+    fn asks_for_bodies_the_client_is_holding_back() {
+        let mut decoder = HttpRequestDecoder::new(8 * 1024, 1024);
+        let head = "POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+        assert!(decoder.decode(head.as_bytes()).unwrap().is_empty());
+        assert!(decoder.take_continue());
+        assert!(!decoder.take_continue());
+        assert_eq!(decoder.decode(b"hello").unwrap()[0].body, b"hello");
+
+        // Not once the body has arrived, without a body or from HTTP/1.0 clients
+        let requests = [
+            "POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\n\r\n",
+            "POST / HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for request in requests {
+            let mut decoder = HttpRequestDecoder::new(8 * 1024, 1024);
+            decoder.decode(request.as_bytes()).unwrap();
+            assert!(!decoder.take_continue(), "{}", request);
+        }
+    }
+}
@@ -1,12 +1,243 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::warn;
 use sha1::{Digest, Sha1};
+use super::request::{is_token, Headers};
 
-pub fn upgrade_to_websocket(upgrade_key: &String) -> String {
-    let accept_key = build_ws_accept_key(upgrade_key);
+/**
+ * An HTTP response: status, headers and body.
+ *
+ * `Content-Length` and `Date` are added when the response is serialized, so they don't need to
+ * be set.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    /// The status code, e.g. `404`.
+    pub status: u16,
+    /// The reason phrase sent after the status code, e.g. `Not Found`.
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Creates an empty response with the given status and its standard reason phrase.
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse {
+            status,
+            reason: String::from(reason_phrase(status)),
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Replaces the reason phrase. Control characters are removed from it.
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = field_value(reason);
+        self
+    }
+
+    /// Adds a header, keeping any values it already has.
+    ///
+    /// Control characters (CR and LF among them) are removed from the value, so it can't end
+    /// the header early, and a header whose name is not a valid field name is dropped.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        if !is_token(name) {
+            warn!("[HTTP Response] Dropping header with invalid name {0:?}.", name);
+            return self;
+        }
+        self.headers.append(name, &field_value(value));
+        self
+    }
+
+    /// Sets the body and its `Content-Type`.
+    pub fn with_body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.headers.set("Content-Type", &field_value(content_type));
+        self.body = body.into();
+        self
+    }
+
+    /// `200 OK` with a plain text body.
+    pub fn text(body: &str) -> HttpResponse {
+        HttpResponse::new(200).with_body("text/plain; charset=utf-8", body)
+    }
+
+    /// `200 OK` with a JSON body.
+    pub fn json(body: &str) -> HttpResponse {
+        HttpResponse::new(200).with_body("application/json", body)
+    }
+
+    /// `200 OK` with an HTML body.
+    pub fn html(body: &str) -> HttpResponse {
+        HttpResponse::new(200).with_body("text/html; charset=utf-8", body)
+    }
+
+    /// `204 No Content`.
+    pub fn no_content() -> HttpResponse {
+        HttpResponse::new(204)
+    }
+
+    /// `301 Moved Permanently` to the given location (see `with_header` for how the location
+    /// is sanitized).
+    pub fn moved_permanently(location: &str) -> HttpResponse {
+        HttpResponse::new(301).with_header("Location", location)
+    }
+
+    /// `302 Found`, redirecting to the given location (see `with_header` for how the location
+    /// is sanitized).
+    pub fn found(location: &str) -> HttpResponse {
+        HttpResponse::new(302).with_header("Location", location)
+    }
+
+    /// `400 Bad Request`.
+    pub fn bad_request() -> HttpResponse {
+        HttpResponse::new(400)
+    }
 
-    return build_response(accept_key);
+    /// `404 Not Found`.
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404)
+    }
+
+    /// `405 Method Not Allowed`, listing the methods the resource does allow.
+    pub fn method_not_allowed(allowed: &[&str]) -> HttpResponse {
+        HttpResponse::new(405).with_header("Allow", &allowed.join(", "))
+    }
+
+    /// `500 Internal Server Error`.
+    pub fn internal_server_error() -> HttpResponse {
+        HttpResponse::new(500)
+    }
+
+    /**
+     * Serializes the response as HTTP/1.1.
+     *
+     * `Content-Length` is set from the body (except on 1xx and 204 responses, which have no
+     * body) and `Date` is set to the current time unless the response already has one.
+     * Headers added to `headers` directly are sanitized as `with_header` does.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_head_bytes();
+        if self.has_body() {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+
+    /// Serializes the status line and headers only, as sent in answer to a HEAD request.
+    pub fn to_head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {0} {1}\r\n", self.status, field_value(&self.reason));
+        for (name, value) in self.headers.iter() {
+            if name.eq_ignore_ascii_case("Content-Length") || !is_token(name) {
+                continue;
+            }
+            head.push_str(&format!("{0}: {1}\r\n", name, field_value(value)));
+        }
+        if !self.headers.contains("Date") {
+            head.push_str(&format!("Date: {0}\r\n", http_date(SystemTime::now())));
+        }
+        if self.has_body() {
+            head.push_str(&format!("Content-Length: {0}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// Whether the status allows a body (1xx and 204 responses have none).
+    fn has_body(&self) -> bool {
+        !(100..200).contains(&self.status) && self.status != 204
+    }
 }
 
-fn build_ws_accept_key(upgrade_key: &String) -> String {
+/// Removes the control characters (other than tab) from a header value or reason phrase.
+fn field_value(value: &str) -> String {
+    value.chars().filter(|&c| c == '\t' || !c.is_ascii_control()).collect()
+}
+
+/// Returns the standard reason phrase for a status code (RFC 9110), or an empty string for
+/// codes it doesn't define.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Formats a time as an HTTP date (IMF-fixdate), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let days = seconds / 86400;
+    let seconds_of_day = seconds % 86400;
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let shifted = days + 719468;
+    let era = shifted / 146097;
+    let day_of_era = shifted % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{0}, {1:02} {2} {3} {4:02}:{5:02}:{6:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Response accepting a request to upgrade to a WebSocket.
+pub fn upgrade_to_websocket(upgrade_key: &str) -> HttpResponse {
+    HttpResponse::new(101)
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &build_ws_accept_key(upgrade_key))
+        .with_header("Upgrade", "websocket")
+}
+
+fn build_ws_accept_key(upgrade_key: &str) -> String {
     // Calculate accept key
     let mut hasher = Sha1::new();
     let appended = format!(
@@ -15,19 +246,136 @@ fn build_ws_accept_key(upgrade_key: &String) -> String {
     );
     hasher.input(appended.as_bytes());
     let hashed_result = hasher.result();
-    let accept_key = base64::encode(hashed_result);
+    base64::encode(hashed_result)
+}
 
-    return accept_key;
+/// Response sent to connections refused because the server is at capacity.
+pub fn service_unavailable() -> HttpResponse {
+    HttpResponse::new(503).with_header("Connection", "close")
 }
 
-fn build_response(accept_key: String) -> String {
-    let response = std::fmt::format(format_args!(
-        "HTTP/1.1 101 Switching Protocols\r\n\
-        Connection: Upgrade\r\n\
-        Sec-WebSocket-Accept: {}\r\n\
-        Upgrade: websocket\r\n\r\n",
-        accept_key
-    ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn date(seconds: u64) -> String {
+        http_date(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    /// Serializes a response with a fixed date, so the output can be compared.
+    fn serialize(response: HttpResponse) -> String {
+        let response = response.with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT");
+        String::from_utf8(response.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(1735689599), "Tue, 31 Dec 2024 23:59:59 GMT");
+        assert_eq!(date(4107542400), "Mon, 01 Mar 2100 00:00:00 GMT");
+        assert_eq!(date(253402300799), "Fri, 31 Dec 9999 23:59:59 GMT");
+    }
+
+    #[test]
+    fn formats_times_before_the_epoch_as_the_epoch() {
+        assert_eq!(http_date(UNIX_EPOCH - Duration::from_secs(1)), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn serializes_responses_with_a_content_length() {
+        assert_eq!(
+            serialize(HttpResponse::text("Hello")),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Date: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 5\r\n\r\nHello"
+        );
+        assert_eq!(
+            serialize(HttpResponse::not_found()),
+            "HTTP/1.1 404 Not Found\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn replaces_content_length_set_by_the_application() {
+        let response = HttpResponse::text("Hello").with_header("Content-Length", "100");
+        assert!(serialize(response).ends_with("\r\nContent-Length: 5\r\n\r\nHello"));
+    }
+
+    #[test]
+    fn adds_a_date_unless_the_response_has_one() {
+        let head = String::from_utf8(HttpResponse::no_content().to_bytes()).unwrap();
+        let dates: Vec<&str> = head.lines().filter(|line| line.starts_with("Date: ")).collect();
+        assert_eq!(dates.len(), 1);
+        assert!(dates[0].ends_with(" GMT"));
+
+        assert_eq!(serialize(HttpResponse::no_content()).matches("Date: ").count(), 1);
+    }
+
+    #[test]
+    fn sends_no_body_or_content_length_with_204_and_1xx_responses() {
+        for status in [100, 101, 199, 204] {
+            let response = HttpResponse::new(status).with_body("text/plain", "ignored");
+            let serialized = serialize(response);
+            assert!(serialized.ends_with("GMT\r\n\r\n"), "{}", serialized);
+            assert!(!serialized.contains("Content-Length"), "{}", serialized);
+        }
+    }
+
+    #[test]
+    fn sends_the_content_length_but_not_the_body_in_answer_to_head() {
+        let response = HttpResponse::text("Hello").with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT");
+        let head = String::from_utf8(response.to_head_bytes()).unwrap();
+        assert!(head.ends_with("\r\nContent-Length: 5\r\n\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn strips_control_characters_from_header_values() {
+        let response = HttpResponse::found("/next\r\nSet-Cookie: session=stolen\r\n\r\n<script>");
+        assert_eq!(response.headers.get("Location"), Some("/nextSet-Cookie: session=stolen<script>"));
+        let serialized = serialize(response);
+        assert!(serialized.starts_with(
+            "HTTP/1.1 302 Found\r\nLocation: /nextSet-Cookie: session=stolen<script>\r\n"
+        ));
+
+        let response = HttpResponse::moved_permanently("/a\nb\0c\x7f\td");
+        assert_eq!(response.headers.get("Location"), Some("/abc\td"));
+
+        let response = HttpResponse::new(200).with_body("text/plain\r\nX-Injected: 1", "");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plainX-Injected: 1"));
+    }
+
+    #[test]
+    fn drops_headers_with_invalid_names() {
+        let response = HttpResponse::new(200)
+            .with_header("X-Ok", "1")
+            .with_header("X-Bad\r\nSet-Cookie", "a=b")
+            .with_header("Bad Name", "1")
+            .with_header("", "1");
+        assert_eq!(response.headers.len(), 1);
+        assert_eq!(response.headers.get("X-Ok"), Some("1"));
+    }
+
+    #[test]
+    fn sanitizes_headers_and_reasons_set_directly() {
+        let mut response = HttpResponse::new(200);
+        response.reason = String::from("OK\r\nX-Injected: 1");
+        response.headers.append("X-Value", "a\r\nX-Injected: 2");
+        response.headers.append("X:Name", "3");
+        let serialized = serialize(response);
+        assert!(serialized.starts_with("HTTP/1.1 200 OKX-Injected: 1\r\nX-Value: aX-Injected: 2\r\nDate: "), "{}", serialized);
+        assert!(!serialized.contains("X:Name"));
+
+        let response = HttpResponse::new(400).with_reason("Bad\r\n\r\nRequest");
+        assert_eq!(response.reason, "BadRequest");
+    }
 
-    return response;
+    #[test]
+    fn accepts_websocket_upgrades_with_the_expected_key() {
+        // The example from RFC 6455 section 1.3
+        let response = upgrade_to_websocket("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(response.status, 101);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    }
 }
@@ -0,0 +1,68 @@
+use std::collections::{HashMap, HashSet};
+
+/**
+ * Named groups of clients that messages can be published to.
+ *
+ * Rooms are created when their first client joins and removed when their last client leaves.
+ */
+#[derive(Default)]
+pub struct Rooms {
+    /// The clients in each room.
+    members: HashMap<String, HashSet<String>>,
+    /// The rooms each client is in.
+    memberships: HashMap<String, HashSet<String>>,
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms::default()
+    }
+
+    /// Adds a client to a room.
+    pub fn join(&mut self, room: &str, client_id: &str) {
+        self.members
+            .entry(String::from(room))
+            .or_default()
+            .insert(String::from(client_id));
+        self.memberships
+            .entry(String::from(client_id))
+            .or_default()
+            .insert(String::from(room));
+    }
+
+    /// Removes a client from a room.
+    pub fn leave(&mut self, room: &str, client_id: &str) {
+        if let Some(members) = self.members.get_mut(room) {
+            members.remove(client_id);
+            if members.is_empty() {
+                self.members.remove(room);
+            }
+        }
+        if let Some(rooms) = self.memberships.get_mut(client_id) {
+            rooms.remove(room);
+            if rooms.is_empty() {
+                self.memberships.remove(client_id);
+            }
+        }
+    }
+
+    /// Removes a client from every room it is in (e.g. when it disconnects).
+    pub fn leave_all(&mut self, client_id: &str) {
+        for room in self.memberships.remove(client_id).unwrap_or_default() {
+            if let Some(members) = self.members.get_mut(&room) {
+                members.remove(client_id);
+                if members.is_empty() {
+                    self.members.remove(&room);
+                }
+            }
+        }
+    }
+
+    /// Returns the ids of the clients in a room.
+    pub fn members(&self, room: &str) -> Vec<String> {
+        self.members
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
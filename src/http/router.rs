@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use log::debug;
+use super::request::{percent_decode, HttpRequest};
+use super::response::HttpResponse;
+use super::tcp_client_handler::{ConnectionInfo, DisconnectReason};
+use super::websocket_codec::CloseCode;
+use crate::client_handler::ClientHandler;
+#[cfg(feature = "async")]
+use crate::client_handler::AsyncClientHandler;
+use crate::error::Error;
+
+/// An HTTP request matched to a route, as passed to the route's handler.
+pub struct RouteRequest<'a> {
+    pub client_id: &'a str,
+    pub connection: &'a ConnectionInfo,
+    pub request: &'a HttpRequest,
+    /// Values of the route's `:name` and `*name` segments, in pattern order.
+    pub params: Vec<(String, String)>,
+    /// Decoded query string values, in the order they were sent.
+    pub query: Vec<(String, String)>,
+}
+
+impl RouteRequest<'_> {
+    /// Returns the value of a path parameter, e.g. `id` for `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_value(&self.params, name)
+    }
+
+    /// Returns the first value of a query string parameter.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        find_value(&self.query, name)
+    }
+}
+
+fn find_value<'a>(values: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    values
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Answers a routed HTTP request (or returns `None` to answer later with `Action::Respond`).
+type RouteHandler = Box<dyn Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync>;
+
+/// A segment of a route pattern.
+enum Segment {
+    /// Matches exactly this text.
+    Literal(String),
+    /// `:name` matches any one (non-empty) segment.
+    Param(String),
+    /// `*name` matches the rest of the path, which may be empty but never has a `..` segment.
+    Rest(String),
+}
+
+/// A parsed route pattern such as `/users/:id` or `/static/*path`.
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        let segments: Vec<Segment> = split_path(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(String::from(name))
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(String::from(name))
+                } else {
+                    Segment::Literal(String::from(segment))
+                }
+            })
+            .collect();
+        let rest = segments.iter().position(|segment| matches!(segment, Segment::Rest(_)));
+        assert!(
+            rest.is_none() || rest == Some(segments.len() - 1),
+            "A *name segment must come last in route pattern {}",
+            pattern
+        );
+        Pattern { segments }
+    }
+
+    /**
+     * Matches a raw (percent-encoded) request path, returning the decoded values of the
+     * pattern's parameters.
+     *
+     * The path is split into segments before each one is decoded, so an encoded `/` (`%2F`)
+     * stays inside its segment. Paths with a segment that doesn't decode match nothing.
+     */
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut parts = split_path(path);
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest(name) => {
+                    let rest: Option<Vec<String>> = parts.by_ref().map(percent_decode).collect();
+                    let rest = rest?.join("/");
+                    // Never lead a handler (e.g. one serving files) out of its directory
+                    if rest.split('/').any(|part| part == "..") {
+                        return None;
+                    }
+                    params.push((name.clone(), rest));
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.push((name.clone(), percent_decode(part)?));
+                }
+                Segment::Literal(literal) => {
+                    if percent_decode(parts.next()?)? != *literal {
+                        return None;
+                    }
+                }
+            }
+        }
+        // The whole path has to be used up
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Splits a path into its segments (`/` has a single empty segment).
+fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+struct Route {
+    method: String,
+    pattern: Pattern,
+    handler: RouteHandler,
+}
+
+struct WebSocketRoute<H> {
+    pattern: Pattern,
+    handler: H,
+}
+
+/**
+ * Sends requests to handlers by method and path.
+ *
+ * HTTP routes are closures registered for a method and a pattern, in which `:name` matches one
+ * path segment and a final `*name` matches the rest of the path. Requests are matched segment
+ * by segment against the raw path, decoding each segment (so `%2F` does not split one), and
+ * a `*name` never matches a path with a `..` segment. Routes are tried in the order they were
+ * added. Requests that match no route are answered with `404 Not Found`, and requests whose
+ * path matches only routes for other methods with `405 Method Not Allowed` (listing those
+ * methods in `Allow`). `GET` routes also answer `HEAD` requests.
+ *
+ * WebSocket routes hand upgraded connections to a handler by path: the client's messages,
+ * close and disconnect go to the handler of the route it upgraded on. Upgrades on paths with no
+ * WebSocket route are refused with `404 Not Found`. Routes share the handler type `H`; use
+ * `Box<dyn ClientHandler + Send + Sync>` to route to handlers of different types.
+ *
+ * The router is itself a `ClientHandler` (and, with the `async` feature, an
+ * `AsyncClientHandler` when `H` is), so it can be given to the server directly.
+ */
+pub struct Router<H> {
+    routes: Vec<Route>,
+    websocket_routes: Vec<WebSocketRoute<H>>,
+    /// The WebSocket route (index into `websocket_routes`) each upgraded client is using.
+    websocket_clients: Mutex<HashMap<String, usize>>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Router {
+            routes: Vec::new(),
+            websocket_routes: Vec::new(),
+            websocket_clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Router<H> {
+        Router::default()
+    }
+
+    /**
+     * Adds an HTTP route. The handler returns the response, or `None` to answer later with
+     * `Action::Respond` (using the request's client id).
+     *
+     * Panics if a `*name` segment is not the last segment of the pattern.
+     */
+    pub fn route<F>(mut self, method: &str, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: String::from(method),
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Adds a `GET` route (which also answers `HEAD` requests).
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Adds a `POST` route.
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Adds a `PUT` route.
+    pub fn put<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Adds a `DELETE` route.
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&RouteRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Adds a WebSocket route: clients that upgrade on a matching path are handled by `handler`.
+    pub fn websocket(mut self, pattern: &str, handler: H) -> Self {
+        self.websocket_routes.push(WebSocketRoute {
+            pattern: Pattern::parse(pattern),
+            handler,
+        });
+        self
+    }
+
+    /**
+     * Answers an HTTP request with the matching route's handler, or with `404 Not Found` or
+     * `405 Method Not Allowed` if no route matches.
+     */
+    pub fn handle_http_request(
+        &self,
+        client_id: &str,
+        connection: &ConnectionInfo,
+        request: &HttpRequest,
+    ) -> Option<HttpResponse> {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.raw_path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            if route.method == request.verb || (route.method == "GET" && request.verb == "HEAD") {
+                let routed = RouteRequest {
+                    client_id,
+                    connection,
+                    request,
+                    params,
+                    query: request.query_params(),
+                };
+                return (route.handler)(&routed);
+            }
+
+            // The path is known, just not for this method
+            let head = if route.method == "GET" { Some("HEAD") } else { None };
+            for method in std::iter::once(route.method.as_str()).chain(head) {
+                if !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            debug!("[Router] No route for {0} {1}.", request.verb, request.path);
+            Some(HttpResponse::not_found())
+        } else {
+            debug!("[Router] {0} is not allowed for {1}.", request.verb, request.path);
+            Some(HttpResponse::method_not_allowed(&allowed))
+        }
+    }
+
+    /// Returns the index of the first WebSocket route matching a path.
+    fn find_websocket_route(&self, path: &str) -> Option<usize> {
+        self.websocket_routes
+            .iter()
+            .position(|route| route.pattern.matches(path).is_some())
+    }
+
+    /// Accepts upgrades on paths with a WebSocket route and refuses the rest with 404.
+    fn accept_upgrade(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+        match self.find_websocket_route(request.raw_path()) {
+            Some(_) => Ok(()),
+            None => {
+                debug!("[Router] No WebSocket route for {0}.", request.path);
+                Err(HttpResponse::not_found())
+            }
+        }
+    }
+
+    /// Routes an upgraded client by the path it upgraded on, returning the route's handler.
+    fn route_websocket_client(&self, client_id: &str, request: &HttpRequest) -> Option<&H> {
+        let index = self.find_websocket_route(request.raw_path())?;
+        self.clients().insert(String::from(client_id), index);
+        Some(&self.websocket_routes[index].handler)
+    }
+
+    /// Returns the handler of the route an upgraded client is using.
+    fn websocket_handler(&self, client_id: &str) -> Option<&H> {
+        let index = *self.clients().get(client_id)?;
+        Some(&self.websocket_routes[index].handler)
+    }
+
+    /// Forgets a client, returning the handler of the route it was using.
+    fn remove_websocket_client(&self, client_id: &str) -> Option<&H> {
+        let index = self.clients().remove(client_id)?;
+        Some(&self.websocket_routes[index].handler)
+    }
+
+    fn clients(&self) -> MutexGuard<'_, HashMap<String, usize>> {
+        // The table is only changed by single inserts and removes, so it is never left
+        // half-updated by a panic
+        self.websocket_clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<H: ClientHandler> ClientHandler for Router<H> {
+    /// Clients are only routed once they upgrade to a WebSocket.
+    fn on_client_connected(&self, _client_id: &str, _connection: &ConnectionInfo) {}
+
+    fn on_message_received(&self, client_id: &str, message: &str) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_message_received(client_id, message);
+        }
+    }
+
+    fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        if let Some(handler) = self.remove_websocket_client(client_id) {
+            handler.on_client_disconnected(client_id, reason);
+        }
+    }
+
+    fn on_error(&self, client_id: &str, error: &Error) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_error(client_id, error);
+        }
+    }
+
+    fn on_websocket_upgrade_requested(&self, _client_id: &str, _connection: &ConnectionInfo, request: &HttpRequest) -> Result<(), HttpResponse> {
+        self.accept_upgrade(request)
+    }
+
+    fn on_websocket_upgraded(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) {
+        if let Some(handler) = self.route_websocket_client(client_id, request) {
+            handler.on_websocket_upgraded(client_id, connection, request);
+        }
+    }
+
+    fn on_http_request(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) -> Option<HttpResponse> {
+        self.handle_http_request(client_id, connection, request)
+    }
+
+    fn on_binary_message_received(&self, client_id: &str, data: &[u8]) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_binary_message_received(client_id, data);
+        }
+    }
+
+    fn on_websocket_closed(&self, client_id: &str, code: CloseCode, reason: &str) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_websocket_closed(client_id, code, reason);
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncClientHandler> AsyncClientHandler for Router<H> {
+    /// Clients are only routed once they upgrade to a WebSocket.
+    async fn on_client_connected(&self, _client_id: &str, _connection: &ConnectionInfo) {}
+
+    async fn on_message_received(&self, client_id: &str, message: &str) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_message_received(client_id, message).await;
+        }
+    }
+
+    async fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        if let Some(handler) = self.remove_websocket_client(client_id) {
+            handler.on_client_disconnected(client_id, reason).await;
+        }
+    }
+
+    async fn on_error(&self, client_id: &str, error: &Error) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_error(client_id, error).await;
+        }
+    }
+
+    async fn on_websocket_upgrade_requested(&self, _client_id: &str, _connection: &ConnectionInfo, request: &HttpRequest) -> Result<(), HttpResponse> {
+        self.accept_upgrade(request)
+    }
+
+    async fn on_websocket_upgraded(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) {
+        if let Some(handler) = self.route_websocket_client(client_id, request) {
+            handler.on_websocket_upgraded(client_id, connection, request).await;
+        }
+    }
+
+    async fn on_http_request(&self, client_id: &str, connection: &ConnectionInfo, request: &HttpRequest) -> Option<HttpResponse> {
+        self.handle_http_request(client_id, connection, request)
+    }
+
+    async fn on_binary_message_received(&self, client_id: &str, data: &[u8]) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_binary_message_received(client_id, data).await;
+        }
+    }
+
+    async fn on_websocket_closed(&self, client_id: &str, code: CloseCode, reason: &str) {
+        if let Some(handler) = self.websocket_handler(client_id) {
+            handler.on_websocket_closed(client_id, code, reason).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::parse_http_request;
+
+    fn request(verb: &str, path: &str) -> HttpRequest {
+        let data = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", verb, path);
+        parse_http_request(data.as_bytes()).unwrap().unwrap().0
+    }
+
+    /// Routes a request, returning the response and its body as text.
+    fn handle(router: &Router<()>, verb: &str, path: &str) -> (HttpResponse, String) {
+        let connection = ConnectionInfo::new("127.0.0.1:4000".parse().unwrap());
+        let response = router.handle_http_request("client", &connection, &request(verb, path)).unwrap();
+        let body = String::from_utf8(response.body.clone()).unwrap();
+        (response, body)
+    }
+
+    /// Answers with the request's path parameters, e.g. `id=7`.
+    fn echo_params(request: &RouteRequest) -> Option<HttpResponse> {
+        let params: Vec<String> = request.params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        Some(HttpResponse::text(&params.join("&")))
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .get("/", |_| Some(HttpResponse::text("index")))
+            .get("/users/:id", echo_params)
+            .post("/users/:id", |_| Some(HttpResponse::no_content()))
+            .get("/static/*path", echo_params)
+    }
+
+    #[test]
+    fn matches_exact_paths() {
+        let router = router();
+        let (response, body) = handle(&router, "GET", "/");
+        assert_eq!(response.status, 200);
+        assert_eq!(body, "index");
+        assert_eq!(handle(&router, "GET", "/missing").0.status, 404);
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let router = router();
+        assert_eq!(handle(&router, "GET", "/users/7").1, "id=7");
+        // Segments are decoded after the path is split
+        assert_eq!(handle(&router, "GET", "/users/a%2Fb").1, "id=a/b");
+        assert_eq!(handle(&router, "GET", "/static/css/site.css").1, "path=css/site.css");
+        assert_eq!(handle(&router, "GET", "/users/").0.status, 404);
+        assert_eq!(handle(&router, "GET", "/users/7/posts").0.status, 404);
+    }
+
+    #[test]
+    fn answers_known_paths_with_the_wrong_method_with_405() {
+        let (response, _) = handle(&router(), "DELETE", "/users/7");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, POST"));
+    }
+
+    #[test]
+    fn answers_head_requests_with_get_routes() {
+        let router = router();
+        let (response, body) = handle(&router, "HEAD", "/users/7");
+        assert_eq!(response.status, 200);
+        assert_eq!(body, "id=7");
+        assert_eq!(handle(&router, "POST", "/users/7").0.status, 204);
+    }
+
+    #[test]
+    fn never_captures_parent_segments_in_the_rest_of_a_path() {
+        let router = router();
+        assert_eq!(handle(&router, "GET", "/static/../secret").0.status, 404);
+        assert_eq!(handle(&router, "GET", "/static/css/%2E%2E/secret").0.status, 404);
+        assert_eq!(handle(&router, "GET", "/static/css/..%2Fsecret").0.status, 404);
+        assert_eq!(handle(&router, "GET", "/static/..css").1, "path=..css");
+    }
+}
@@ -0,0 +1,198 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use log::warn;
+use mio::Waker;
+use super::response::HttpResponse;
+use super::tcp_server::{Action, ClientInfo, Request, ServerEvent, ShutdownStatus};
+use crate::error::Error;
+
+/// Sends requests to a running server and wakes its event loop.
+///
+/// Cheap to clone, so handlers can keep one to reply to their clients.
+#[derive(Clone)]
+pub struct ServerSender {
+    tx: Sender<Request>,
+    waker: Arc<Waker>
+}
+
+impl ServerSender {
+    pub(super) fn new(tx: Sender<Request>, waker: Arc<Waker>) -> ServerSender {
+        ServerSender { tx, waker }
+    }
+
+    /// Queues a request for the server. Fails if the server has stopped.
+    pub fn send_request(&self, request: Request) -> Result<(), Error> {
+        self.tx.send(request)?;
+        if let Err(error) = self.waker.wake() {
+            warn!("[Server] Error waking server. Error: {0}", error);
+        }
+        Ok(())
+    }
+
+    /// Asks the server to carry out an action for a client.
+    pub fn send(&self, client_id: &str, action: Action) -> Result<(), Error> {
+        self.send_request(Request {
+            client_id: String::from(client_id),
+            action
+        })
+    }
+
+    /// Answers the HTTP request a client is waiting on.
+    pub fn respond(&self, client_id: &str, response: HttpResponse) -> Result<(), Error> {
+        self.send(client_id, Action::Respond(response))
+    }
+
+    /// Sends a text message to every connected WebSocket client.
+    pub fn broadcast(&self, message: &str) -> Result<(), Error> {
+        self.send("", Action::Broadcast(String::from(message)))
+    }
+
+    /// Sends a text message to every connected WebSocket client except the given one.
+    pub fn broadcast_except(&self, client_id: &str, message: &str) -> Result<(), Error> {
+        self.send(client_id, Action::BroadcastExcept(String::from(message)))
+    }
+
+    /// Sends a text message to each of the given WebSocket clients.
+    pub fn multicast(&self, client_ids: Vec<String>, message: &str) -> Result<(), Error> {
+        self.send("", Action::Multicast(client_ids, String::from(message)))
+    }
+
+    /// Adds a client to a room, creating the room if needed.
+    pub fn join_room(&self, client_id: &str, room: &str) -> Result<(), Error> {
+        self.send(client_id, Action::JoinRoom(String::from(room)))
+    }
+
+    /// Removes a client from a room.
+    pub fn leave_room(&self, client_id: &str, room: &str) -> Result<(), Error> {
+        self.send(client_id, Action::LeaveRoom(String::from(room)))
+    }
+
+    /// Sends a text message to every client in a room.
+    pub fn publish(&self, room: &str, message: &str) -> Result<(), Error> {
+        self.send("", Action::Publish(String::from(room), String::from(message)))
+    }
+
+    /// Asks the server for its current clients and waits for the answer.
+    ///
+    /// Blocks until the server has handled the request, so it must not be called from the
+    /// handler (which runs on the server's thread): send `Action::ListClients` from there
+    /// instead, and wait for the answer on another thread.
+    pub fn list_clients(&self) -> Result<Vec<ClientInfo>, Error> {
+        let (reply_tx, reply_rx) = channel();
+        self.send("", Action::ListClients(reply_tx))?;
+        reply_rx.recv().map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Asks the server to disconnect its clients and stop.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.send("", Action::Stop)
+    }
+}
+
+/**
+ * A running server.
+ *
+ * Owns the channels used to talk to the server and the thread it runs on.
+ */
+pub struct ServerHandle {
+    sender: ServerSender,
+    events_rx: Receiver<ServerEvent>,
+    local_addr: SocketAddr,
+    thread: JoinHandle<ShutdownStatus>
+}
+
+impl ServerHandle {
+    pub(super) fn new(
+        sender: ServerSender,
+        events_rx: Receiver<ServerEvent>,
+        local_addr: SocketAddr,
+        thread: JoinHandle<ShutdownStatus>
+    ) -> ServerHandle {
+        ServerHandle { sender, events_rx, local_addr, thread }
+    }
+
+    /// The address the server is listening on. When started on port 0 this has the port the
+    /// operating system picked.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns a sender for queuing requests from other threads.
+    pub fn sender(&self) -> ServerSender {
+        self.sender.clone()
+    }
+
+    /// Events the server reports. The only one is `ServerEvent::Stopped`, sent once the server
+    /// has stopped and all of its clients have disconnected.
+    pub fn events(&self) -> &Receiver<ServerEvent> {
+        &self.events_rx
+    }
+
+    /// Asks the server to carry out an action for a client.
+    pub fn send(&self, client_id: &str, action: Action) -> Result<(), Error> {
+        self.sender.send(client_id, action)
+    }
+
+    /// Answers the HTTP request a client is waiting on.
+    pub fn respond(&self, client_id: &str, response: HttpResponse) -> Result<(), Error> {
+        self.sender.respond(client_id, response)
+    }
+
+    /// Sends a text message to every connected WebSocket client.
+    pub fn broadcast(&self, message: &str) -> Result<(), Error> {
+        self.sender.broadcast(message)
+    }
+
+    /// Sends a text message to every connected WebSocket client except the given one.
+    pub fn broadcast_except(&self, client_id: &str, message: &str) -> Result<(), Error> {
+        self.sender.broadcast_except(client_id, message)
+    }
+
+    /// Sends a text message to each of the given clients.
+    pub fn multicast(&self, client_ids: Vec<String>, message: &str) -> Result<(), Error> {
+        self.sender.multicast(client_ids, message)
+    }
+
+    /// Adds a client to a room, creating the room if needed.
+    pub fn join_room(&self, client_id: &str, room: &str) -> Result<(), Error> {
+        self.sender.join_room(client_id, room)
+    }
+
+    /// Removes a client from a room.
+    pub fn leave_room(&self, client_id: &str, room: &str) -> Result<(), Error> {
+        self.sender.leave_room(client_id, room)
+    }
+
+    /// Sends a text message to every client in a room.
+    pub fn publish(&self, room: &str, message: &str) -> Result<(), Error> {
+        self.sender.publish(room, message)
+    }
+
+    /// Asks the server for its current clients and waits for the answer.
+    pub fn list_clients(&self) -> Result<Vec<ClientInfo>, Error> {
+        self.sender.list_clients()
+    }
+
+    /// Asks the server to disconnect its clients and stop. Use `join` to wait for it.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.sender.stop()
+    }
+
+    /// The server's thread.
+    pub fn thread(&self) -> &JoinHandle<ShutdownStatus> {
+        &self.thread
+    }
+
+    /// Gives up the handle, keeping only the server's thread. The server keeps running until a
+    /// `ServerSender` asks it to stop.
+    pub fn into_thread(self) -> JoinHandle<ShutdownStatus> {
+        self.thread
+    }
+
+    /// Waits for the server thread to finish and returns how the shutdown went.
+    pub fn join(self) -> std::thread::Result<ShutdownStatus> {
+        self.thread.join()
+    }
+}
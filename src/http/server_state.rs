@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use log::{debug, warn};
+use super::request::HttpRequest;
+use super::response::{self, HttpResponse};
+use super::rooms::Rooms;
+use super::tcp_client_handler::{ClientEvent, ConnectionInfo, DisconnectReason, TcpClientType};
+use super::tcp_server::{Action, ClientInfo, Request};
+use super::websocket_codec::CloseCode;
+use crate::error::Error;
+
+/// How the server reaches a client (e.g. the worker thread or task driving its connection).
+pub(crate) trait ClientLink {
+    /// Identifies the link. Clients with the same key share a link, so messages for all of
+    /// them are sent over it once.
+    fn key(&self) -> usize;
+
+    /// Queues a request for the client. Fails if the client can no longer be reached and will
+    /// never report its own disconnect.
+    fn send(&self, request: Request) -> Result<(), Error>;
+
+    /// Sends a text message shared by the given clients, all of which use this link.
+    fn send_shared(&self, client_ids: Vec<String>, message: Arc<str>) -> Result<(), Error>;
+}
+
+/// Something the application is told about a client (a `ClientHandler` callback).
+pub(crate) enum Notification {
+    Connected(ConnectionInfo),
+    Message(String),
+    Binary(Vec<u8>),
+    Closed(CloseCode, String),
+    /// Answered with `ServerState::answer_upgrade`.
+    UpgradeRequested(ConnectionInfo, Box<HttpRequest>),
+    Upgraded(ConnectionInfo, Box<HttpRequest>),
+    /// Answered now or later with `ServerState::respond`.
+    HttpRequest(ConnectionInfo, Box<HttpRequest>),
+    Error(Error),
+    Disconnected(DisconnectReason),
+}
+
+/// What the server has to do after the state has handled a request or event.
+pub(crate) enum Effect {
+    /// Notify the handler about a client.
+    Notify(String, Notification),
+    /// Stop the server.
+    Stop,
+}
+
+struct Client<L> {
+    connection: ConnectionInfo,
+    /// Whether the handler has been told about the client.
+    is_connected: bool,
+    link: L,
+}
+
+/**
+ * The server's clients and rooms, independent of how connections are driven.
+ *
+ * The state never calls the application itself: requests and client events are turned into
+ * effects (e.g. handler notifications), so the same logic serves both the
+ * threaded and the async server.
+ */
+pub(crate) struct ServerState<L: ClientLink> {
+    name: String,
+    clients: HashMap<String, Client<L>>,
+    /// Rooms the application has put clients in.
+    rooms: Rooms,
+    max_connections: usize,
+}
+
+impl<L: ClientLink> ServerState<L> {
+    pub fn new(name: &str, max_connections: usize) -> ServerState<L> {
+        ServerState {
+            name: String::from(name),
+            clients: HashMap::new(),
+            rooms: Rooms::new(),
+            max_connections,
+        }
+    }
+
+    /// Connections accepted but not yet reported as disconnected.
+    pub fn active_connections(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Whether new connections have to be refused.
+    pub fn is_full(&self) -> bool {
+        self.clients.len() >= self.max_connections
+    }
+
+    /**
+     * Starts tracking a newly accepted client.
+     */
+    pub fn add_client(&mut self, client_id: String, address: std::net::SocketAddr, link: L) {
+        let client = Client {
+            connection: ConnectionInfo::new(address),
+            is_connected: false,
+            link,
+        };
+        self.clients.insert(client_id, client);
+    }
+
+    /**
+     * Forgets every remaining client, returning their links (e.g. to close the connections
+     * that outlived the drain period).
+     */
+    #[cfg(feature = "async")]
+    pub fn take_clients(&mut self) -> Vec<(String, L)> {
+        self.rooms = Rooms::new();
+        self.clients
+            .drain()
+            .map(|(client_id, client)| (client_id, client.link))
+            .collect()
+    }
+
+    /**
+     * Carries out a request from the application.
+     */
+    pub fn handle_request(&mut self, request: Request) -> Vec<Effect> {
+        match request.action {
+            Action::SendMessage(_) | Action::SendBinary(_) | Action::Close(_, _) | Action::Respond(_) => {
+                let client_id = request.client_id.clone();
+                let client = match self.clients.get(&client_id) {
+                    Some(client) => client,
+                    None => return self.report_unknown_client(&client_id),
+                };
+                // Messages are sent as WebSocket frames, which would corrupt an HTTP connection
+                let is_message = matches!(request.action, Action::SendMessage(_) | Action::SendBinary(_));
+                if is_message && client.connection.protocol != TcpClientType::WebSocket {
+                    return self.report_not_websocket(client_id, "take messages");
+                }
+                if client.link.send(request).is_err() {
+                    return self.drop_unreachable_client(&client_id);
+                }
+                Vec::new()
+            }
+            Action::Broadcast(message) => {
+                let client_ids = self.websocket_clients(None);
+                self.send_to_clients(client_ids, message)
+            }
+            Action::BroadcastExcept(message) => {
+                let client_ids = self.websocket_clients(Some(&request.client_id));
+                self.send_to_clients(client_ids, message)
+            }
+            Action::Multicast(client_ids, message) => self.send_to_clients(client_ids, message),
+            Action::JoinRoom(room) => {
+                let client = match self.clients.get(&request.client_id) {
+                    Some(client) => client,
+                    None => return self.report_unknown_client(&request.client_id),
+                };
+                // Rooms are published to with text frames, which only WebSocket clients take
+                if client.connection.protocol != TcpClientType::WebSocket {
+                    return self.report_not_websocket(request.client_id, &format!("join room {0}", room));
+                }
+                debug!("[Server] ({0}) Client {1} joined room {2}.", self.name, request.client_id, room);
+                self.rooms.join(&room, &request.client_id);
+                Vec::new()
+            }
+            Action::LeaveRoom(room) => {
+                debug!("[Server] ({0}) Client {1} left room {2}.", self.name, request.client_id, room);
+                self.rooms.leave(&room, &request.client_id);
+                Vec::new()
+            }
+            Action::Publish(room, message) => {
+                let client_ids = self.rooms.members(&room);
+                self.send_to_clients(client_ids, message)
+            }
+            Action::ListClients(reply_tx) => {
+                if reply_tx.send(self.client_list()).is_err() {
+                    debug!("[Server] ({0}) Nobody is waiting for the client list.", self.name);
+                }
+                Vec::new()
+            }
+            Action::Stop => {
+                debug!("[Server] ({0}) Received request to stop server.", self.name);
+                vec![Effect::Stop]
+            }
+        }
+    }
+
+    /**
+     * Updates the client table for an event from one of the clients and returns what the
+     * handler needs to be told.
+     */
+    pub fn handle_client_event(&mut self, client_id: String, event: ClientEvent) -> Vec<Effect> {
+        let client = match self.clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => {
+                warn!("[Server] ({0}) Received event for unknown client {1}.", self.name, client_id);
+                return Vec::new();
+            }
+        };
+        let address = client.connection.peer_address;
+        let notification = match event {
+            ClientEvent::Connected => {
+                // Mark the client as connected
+                client.is_connected = true;
+                Notification::Connected(client.connection.clone())
+            }
+            ClientEvent::Message(message) => {
+                debug!("[{0}] ({1}) Received message from client. Message: {2}", self.name, address, message);
+                Notification::Message(message)
+            }
+            ClientEvent::Binary(data) => {
+                debug!("[{0}] ({1}) Received binary message from client. Length: {2}", self.name, address, data.len());
+                Notification::Binary(data)
+            }
+            ClientEvent::Closed(code, reason) => {
+                debug!(
+                    "[{0}] ({1}) Client closed WebSocket. Code: {2}, Reason: {3}",
+                    self.name, address, code.as_u16(), reason
+                );
+                Notification::Closed(code, reason)
+            }
+            ClientEvent::UpgradeRequested(request) => {
+                debug!("[{0}] ({1}) Client asked to upgrade to WebSocket. Path: {2}", self.name, address, request.path);
+                Notification::UpgradeRequested(client.connection.clone(), request)
+            }
+            ClientEvent::Upgraded(request) => {
+                // The client now speaks WebSocket
+                client.connection.protocol = TcpClientType::WebSocket;
+                client.connection.path = Some(request.path.clone());
+                Notification::Upgraded(client.connection.clone(), request)
+            }
+            ClientEvent::HttpRequest(request) => {
+                debug!(
+                    "[{0}] ({1}) Received HTTP request from client. {2} {3}",
+                    self.name, address, request.verb, request.target
+                );
+                Notification::HttpRequest(client.connection.clone(), request)
+            }
+            ClientEvent::Error(error) => {
+                warn!("[{0}] ({1}) Client communication error: {2}", self.name, address, error);
+                Notification::Error(error)
+            }
+            ClientEvent::Disconnected(reason) => {
+                debug!("[{0}] ({1}) Client disconnected. Reason: {2:?}", self.name, address, reason);
+                // The client is gone for good
+                self.clients.remove(&client_id);
+                self.rooms.leave_all(&client_id);
+                Notification::Disconnected(reason)
+            }
+        };
+        vec![Effect::Notify(client_id, notification)]
+    }
+
+    /**
+     * Sends the handler's answer to the HTTP request a client is waiting on.
+     */
+    pub fn respond(&mut self, client_id: &str, response: HttpResponse) -> Vec<Effect> {
+        self.handle_request(Request {
+            client_id: String::from(client_id),
+            action: Action::Respond(response),
+        })
+    }
+
+    /**
+     * Accepts or refuses a client's request to upgrade to a WebSocket, as the handler decided.
+     */
+    pub fn answer_upgrade(
+        &mut self,
+        client_id: &str,
+        request: &HttpRequest,
+        decision: Result<(), HttpResponse>,
+    ) -> Vec<Effect> {
+        let response = match decision {
+            Ok(()) => response::upgrade_to_websocket(request.headers.get("Sec-WebSocket-Key").unwrap_or_default()),
+            Err(response) => response,
+        };
+        self.respond(client_id, response)
+    }
+
+    /**
+     * Asks every client to disconnect (WebSocket clients are sent a 1001 Going Away close
+     * frame).
+     */
+    pub fn stop_clients(&mut self) -> Vec<Effect> {
+        let mut unreachable = Vec::new();
+        for (client_id, client) in &self.clients {
+            debug!(
+                "[Server] ({0}) Sending disconnect request to client {1} at address {2}.",
+                self.name, client_id, client.connection.peer_address
+            );
+            let stop = Request { client_id: client_id.clone(), action: Action::Stop };
+            if client.link.send(stop).is_err() {
+                unreachable.push(client_id.clone());
+            }
+        }
+        unreachable
+            .iter()
+            .flat_map(|client_id| self.drop_unreachable_client(client_id))
+            .collect()
+    }
+
+    /// Lists the clients in the table.
+    fn client_list(&self) -> Vec<ClientInfo> {
+        self.clients
+            .iter()
+            .map(|(client_id, client)| ClientInfo {
+                client_id: client_id.clone(),
+                connection: client.connection.clone(),
+                is_connected: client.is_connected,
+            })
+            .collect()
+    }
+
+    /**
+     * Returns the ids of the connected WebSocket clients, leaving out the given client (if
+     * any).
+     */
+    fn websocket_clients(&self, except: Option<&str>) -> Vec<String> {
+        self.clients
+            .iter()
+            .filter(|(client_id, client)| {
+                client.is_connected
+                    && client.connection.protocol == TcpClientType::WebSocket
+                    && Some(client_id.as_str()) != except
+            })
+            .map(|(client_id, _)| client_id.clone())
+            .collect()
+    }
+
+    /**
+     * Sends a text message to the given clients. Clients that are not (yet) connected
+     * WebSocket clients are skipped, as a text frame would corrupt their HTTP stream.
+     *
+     * Each link is sent the message once, for all of its clients, and the clients share a
+     * single copy of it.
+     */
+    fn send_to_clients(&mut self, client_ids: Vec<String>, message: String) -> Vec<Effect> {
+        let mut effects = Vec::new();
+
+        // Group the clients by the link they use
+        let mut links: HashMap<usize, (&L, Vec<String>)> = HashMap::new();
+        for client_id in client_ids {
+            match self.clients.get(&client_id) {
+                Some(client) if client.is_connected && client.connection.protocol == TcpClientType::WebSocket => {
+                    links
+                        .entry(client.link.key())
+                        .or_insert_with(|| (&client.link, Vec::new()))
+                        .1
+                        .push(client_id);
+                }
+                Some(_) => {}
+                None => effects.extend(self.report_unknown_client(&client_id)),
+            }
+        }
+
+        let message: Arc<str> = Arc::from(message);
+        let mut unreachable = Vec::new();
+        for (link, client_ids) in links.into_values() {
+            if link.send_shared(client_ids.clone(), Arc::clone(&message)).is_err() {
+                unreachable.extend(client_ids);
+            }
+        }
+        for client_id in unreachable {
+            effects.extend(self.drop_unreachable_client(&client_id));
+        }
+        effects
+    }
+
+    /**
+     * Tells the handler that a request named a client the server does not have (e.g. one that
+     * has disconnected).
+     */
+    fn report_unknown_client(&self, client_id: &str) -> Vec<Effect> {
+        warn!("[Server] ({0}) Received request for unknown client {1}.", self.name, client_id);
+        let error = Error::UnknownClient(String::from(client_id));
+        vec![Effect::Notify(String::from(client_id), Notification::Error(error))]
+    }
+
+    /**
+     * Tells the handler that a request only WebSocket clients can take (a message, or joining
+     * a room) named a client that is not one.
+     */
+    fn report_not_websocket(&self, client_id: String, what: &str) -> Vec<Effect> {
+        warn!("[Server] ({0}) Client {1} can't {2}: it is not a WebSocket client.", self.name, client_id, what);
+        let error = Error::NotWebSocket(client_id.clone());
+        vec![Effect::Notify(client_id, Notification::Error(error))]
+    }
+
+    /**
+     * Drops a client that can no longer be reached.
+     *
+     * The client will never report its own disconnect, so the handler is told about the error
+     * and the disconnect here instead.
+     */
+    fn drop_unreachable_client(&mut self, client_id: &str) -> Vec<Effect> {
+        if self.clients.remove(client_id).is_none() {
+            return Vec::new();
+        }
+        warn!("[Server] ({0}) Lost contact with client {1}.", self.name, client_id);
+        self.rooms.leave_all(client_id);
+
+        let error = Error::ChannelClosed;
+        let reason = DisconnectReason::Error(error.to_string());
+        vec![
+            Effect::Notify(String::from(client_id), Notification::Error(error)),
+            Effect::Notify(String::from(client_id), Notification::Disconnected(reason)),
+        ]
+    }
+}
@@ -1,233 +1,406 @@
 use std::io::{Read, Write};
-use std::sync::mpsc::{TryRecvError, Sender, Receiver};
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, SendError};
+use std::time::{Duration, Instant, SystemTime};
 use log::{debug, warn};
-use super::response;
-use super::http_request_handler::HttpClientRequestHandler;
-use super::websocket_request_handler::WebSocketClientRequestHandler;
-use crate::http::{Request, Action};
+use mio::net::TcpStream;
+use mio::event::Event;
+use mio::{Interest, Registry, Token, Waker};
+use super::client_session::ClientSession;
+use super::request::HttpRequest;
+use super::response::HttpResponse;
+use super::http_request_handler::HttpConfig;
+use super::websocket_request_handler::WebSocketConfig;
+use super::websocket_codec::CloseCode;
+use crate::error::Error;
+use crate::http::Request;
 
+/// How long a client that has been disconnected gets to take the rest of what was sent to it
+/// (e.g. a response ending with `Connection: close`) before its connection is dropped anyway.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Drives a client's session over a non-blocking stream on behalf of a worker.
+ */
 pub struct TcpClientHandler {
-    address: std::net::SocketAddr,
-    is_connected: bool,
-    client_type: TcpClientType,
-    stream: std::net::TcpStream,
-    to_server_tx: Sender<String>,
-    from_server_rx: Receiver<Request>,
-    request_handler: Box<dyn TcpClientRequestHandler + Send>
+    session: ClientSession,
+    stream: TcpStream,
+    token: Token,
+    to_server_tx: ClientEventSender,
+    /// Whether the stream is registered for writable events.
+    is_write_registered: bool,
+    /// When the client, once disconnected, must have taken the rest of the write buffer.
+    flush_deadline: Option<Instant>
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TcpClientType {
     Http,
     WebSocket
 }
 
+/// What the server knows about a client's connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// The client's address.
+    pub peer_address: std::net::SocketAddr,
+    /// When the connection was accepted.
+    pub connected_at: SystemTime,
+    /// The protocol spoken over the connection.
+    pub protocol: TcpClientType,
+    /// Path of the request that upgraded the connection to a WebSocket (if it has been).
+    pub path: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// Describes a newly accepted HTTP connection.
+    pub fn new(peer_address: std::net::SocketAddr) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_address,
+            connected_at: SystemTime::now(),
+            protocol: TcpClientType::Http,
+            path: None,
+        }
+    }
+}
+
+/// Why a client connection ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    ClosedByClient,
+    /// The server closed the connection (at the application's request or because the client
+    /// broke the protocol).
+    ClosedByServer(String),
+    /// The client stopped responding.
+    TimedOut,
+    /// The server is shutting down.
+    ServerShutdown,
+    /// Reading from or writing to the connection failed.
+    Error(String),
+}
+
+/// Events sent from a client handler to the server.
+pub enum ClientEvent {
+    /// The client handler has started.
+    Connected,
+    /// The client asked to upgrade the connection to a WebSocket. The request is accepted or
+    /// refused with `Action::Respond`.
+    UpgradeRequested(Box<HttpRequest>),
+    /// The connection was upgraded to a WebSocket by the given request.
+    Upgraded(Box<HttpRequest>),
+    /// The client sent a plain HTTP request. It is answered with `Action::Respond`.
+    HttpRequest(Box<HttpRequest>),
+    /// The client sent a text message.
+    Message(String),
+    /// The client sent a binary message.
+    Binary(Vec<u8>),
+    /// The WebSocket closing handshake completed with the given status code and reason.
+    Closed(CloseCode, String),
+    /// Communication with the client failed. The connection is closed after this.
+    Error(Error),
+    /// The connection has ended. This is always the last event sent for a client.
+    Disconnected(DisconnectReason)
+}
+
+/// Sends client events (tagged with the client's id) to the server and wakes the server's
+/// event loop.
+#[derive(Clone)]
+pub struct ClientEventSender {
+    tx: Sender<(String, ClientEvent)>,
+    waker: Arc<Waker>
+}
+
+impl ClientEventSender {
+    pub fn new(tx: Sender<(String, ClientEvent)>, waker: Arc<Waker>) -> ClientEventSender {
+        ClientEventSender { tx, waker }
+    }
+
+    pub fn send(&self, client_id: &str, event: ClientEvent) -> Result<(), SendError<(String, ClientEvent)>> {
+        self.tx.send((String::from(client_id), event))?;
+        if let Err(error) = self.waker.wake() {
+            warn!("[TCP Client Handler] Error waking server. Error: {0}", error);
+        }
+        Ok(())
+    }
+}
+
 pub enum TcpClientAction {
-    None,
     HandleMessage(String),
-    CloseConnection,
-    UpgradeToWebSocket(String),
-    RequestServerShutdown
+    HandleBinaryMessage(Vec<u8>),
+    SendData(Vec<u8>),
+    HandleClose(CloseCode, String),
+    CloseConnection(DisconnectReason),
+    /// Passes a request to upgrade to a WebSocket to the application, which accepts or refuses
+    /// it with `Action::Respond`.
+    RequestWebSocketUpgrade(Box<HttpRequest>),
+    /// Switches the connection to the WebSocket protocol (the response accepting the upgrade
+    /// has been sent).
+    UpgradeToWebSocket(Box<HttpRequest>),
+    /// Passes an HTTP request to the application, which answers it with `Action::Respond`.
+    HandleHttpRequest(Box<HttpRequest>),
+    /// Reports the error and closes the connection.
+    HandleError(Error)
 }
 
 pub trait TcpClientRequestHandler {
+    /// Handles bytes read from the client and returns the resulting actions (if any).
     fn handle_request(
-        self: &Self, 
-        stream: &std::net::TcpStream, 
+        &mut self, 
+        stream: &mut dyn Write, 
         data: &[u8], 
-        num_bytes: &usize) -> TcpClientAction;
+        num_bytes: &usize) -> Vec<TcpClientAction>;
 
     fn send_response(
-        self: &Self, 
-        stream: &mut std::net::TcpStream,
-        message: String);
+        &self, 
+        stream: &mut dyn Write,
+        message: &str) -> std::io::Result<()>;
+
+    fn send_binary_response(
+        &self,
+        stream: &mut dyn Write,
+        data: Vec<u8>) -> std::io::Result<()>;
+
+    /// Sends the application's response to the client's HTTP request (if the protocol has
+    /// requests to respond to).
+    fn send_http_response(&mut self, _stream: &mut dyn Write, _response: HttpResponse) -> Vec<TcpClientAction> {
+        Vec::new()
+    }
+
+    /// Starts closing the connection with the given status code (if the protocol supports it).
+    fn close_connection(&mut self, _code: CloseCode, _reason: &str) -> Vec<TcpClientAction> {
+        Vec::new()
+    }
+
+    /// Called whenever the client's event loop wakes so the handler can perform time-based
+    /// work (e.g. heartbeats).
+    fn handle_tick(&mut self) -> Vec<TcpClientAction> {
+        Vec::new()
+    }
+
+    /// Returns when `handle_tick` next needs to be called (if ever).
+    fn next_tick(&self) -> Option<Instant> {
+        None
+    }
 }
 
 impl TcpClientHandler {
     /**
-     * Handles a new TCP client.
+     * Creates the handler for a new TCP client.
+     *
+     * The handler does not own a thread: a worker registers it with its poll and calls it
+     * whenever the stream is ready, the server has a request for it, or a tick is due.
      */
-    pub fn handle_new_client(
-        stream: std::net::TcpStream,
+    pub fn new(
+        stream: TcpStream,
+        client_id: String,
         address: std::net::SocketAddr,
-        client_type: TcpClientType,
-        to_server_tx: Sender<String>,
-        from_server_rx: Receiver<Request>,
-    ) {
-        // Create the TCP client handler for this client
-        let handler = TcpClientHandler {
-            stream: stream,
-            address: address,
-            is_connected: false,
-            client_type: client_type,
+        to_server_tx: ClientEventSender,
+        http_config: &HttpConfig,
+        websocket_config: WebSocketConfig,
+    ) -> TcpClientHandler {
+        TcpClientHandler {
+            session: ClientSession::new(client_id, address, http_config, websocket_config),
+            stream,
+            token: Token(0),
             to_server_tx,
-            from_server_rx,
-            request_handler: Box::new(HttpClientRequestHandler {
-                address: address
-            })
-        };
+            is_write_registered: false,
+            flush_deadline: None
+        }
+    }
+
+    /// Returns the id the server knows this client by.
+    pub fn client_id(&self) -> &str {
+        self.session.client_id()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session.is_connected()
+    }
 
-        // Handle the client
-        handler.handle_client();
+    /**
+     * Whether the client can be finished: it has disconnected and everything sent to it has
+     * been written (or it took too long to take it).
+     */
+    pub fn is_finished(&self) -> bool {
+        if self.is_connected() {
+            return false;
+        }
+        !self.session.has_pending_writes()
+            || self.flush_deadline.is_some_and(|deadline| Instant::now() >= deadline)
     }
 
     /**
      * Begin handling communications with the TCP client.
+     *
+     * Registers the stream with the worker's poll under the given token.
      */
-    fn handle_client(mut self: TcpClientHandler) {
-        // Spawn a thread to handle the new client
-        std::thread::spawn(move || {
-            debug!(
-                "[TCP Client Handler] New client connection from {0}",
-                &self.address
-            );
+    pub fn start(&mut self, registry: &Registry, token: Token) {
+        self.token = token;
+        match registry.register(&mut self.stream, token, Interest::READABLE) {
+            // Mark client as connected
+            Ok(_) => self.session.connect(),
+            Err(error) => self.session.handle_error(Error::Io(error)),
+        }
+        self.send_events();
+    }
 
-            debug!("[Client at {0}] Setting stream to non-blocking.", self.address);
-            self.stream.set_nonblocking(true).expect("Error setting stream to non-blocking.");
+    /**
+     * Handles a readiness event for the client's stream.
+     */
+    pub fn handle_event(&mut self, registry: &Registry, event: &Event, buffer: &mut [u8]) {
+        if event.is_readable() || event.is_read_closed() {
+            self.read_stream(buffer);
+        }
 
-            // Mark client as connected
-            self.is_connected = true;
-            self.to_server_tx
-                .send(String::from("Connected"))
-                .expect("Error notifying server of client connection.");
-
-            let mut buffer = [0 as u8; 4096];
-
-            // Run while the client is connected
-            while self.is_connected {
-                match &self.stream.read(&mut buffer) {
-                    Ok(0) => {
-                        self.handle_disconnect();
-                    }
-                    Ok(size) => {
-                        self.handle_request(&buffer, &size);
-                    }
-                    // Handle case where waiting for accept would become blocking
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                    // Handle error case
-                    Err(error) => {
-                        self.handle_error(&error);
-                        // Mark the client as disconnected
-                        self.is_connected = false;
-                    }
-                }
+        // Send whatever can be sent without blocking
+        self.flush_writes(registry);
+    }
 
-                // Check for messages from server
-                match self.from_server_rx.try_recv() {
-                    Ok(request) => {
-                        match request.action {
-                            Action::SendMessage(message) => {
-                                debug!("[Client @ {0}] Received notification from server to send a message.", self.address);
-                                (*self.request_handler).send_response(&mut self.stream, message);    
-                            }
-                            Action::Stop => {
-                                debug!(
-                                    "[Client @ {0}] Received notification from server to disconnect.",
-                                    self.address
-                                );
-                                self.stream
-                                    .shutdown(std::net::Shutdown::Both)
-                                    .expect("Failed to shutdown client.");
-                                // Mark the client as disconnected
-                                self.is_connected = false;
-                            }
-                        }
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => {
-                        warn!("[Client @ {0}] Error receiving from server.", self.address);
-                    }
-                }
+    /**
+     * Returns when the client next needs a tick (if ever).
+     */
+    pub fn next_tick(&self) -> Option<Instant> {
+        // A disconnected client only waits for its writes
+        if !self.session.is_connected() {
+            return self.flush_deadline;
+        }
+        self.session.next_tick()
+    }
 
-                // Sleep for a short time (let the client do something)
-                std::thread::sleep(std::time::Duration::from_millis(100));
-            }
+    /**
+     * Gives the request handler a chance to do time-based work.
+     */
+    pub fn handle_tick(&mut self, registry: &Registry) {
+        self.session.handle_tick();
+        self.flush_writes(registry);
+    }
 
-            // Finalize disconnect
-            self.to_server_tx.send(String::from("Disconnected")).expect("Error notifying server that client disconnected.");
-        });        
+    /**
+     * Handles a request the server has queued for this client.
+     */
+    pub fn handle_server_request(&mut self, registry: &Registry, request: Request) {
+        self.session.handle_server_request(request);
+        self.flush_writes(registry);
     }
 
     /**
-     * Handles client disconnect.
+     * Sends a text message to the client.
      */
-    fn handle_disconnect(&mut self) {
-        debug!("[TCP Client Handler] ({0}) Disconnected.", &self.address);
-        self.is_connected = false;
-        self.to_server_tx
-            .send(String::from("Client Disconnect"))
-            .expect("Error notifying server of client disconnect.");
+    pub fn send_message(&mut self, registry: &Registry, message: &str) {
+        self.session.send_message(message);
+        self.flush_writes(registry);
     }
 
     /**
-     * Handlers client requests.
+     * Finishes the client once it has disconnected: removes the stream from the worker's poll,
+     * shuts it down and tells the server the client has gone.
      */
-    fn handle_request(&mut self, data: &[u8], size: &usize) {
-        match self.request_handler.handle_request(&self.stream, data, size) {
-            TcpClientAction::None => {},
-            TcpClientAction::CloseConnection => {
-                self.handle_disconnect();
-            }
-            TcpClientAction::HandleMessage(message) => {
-                self.handle_message(message);
-            }
-            TcpClientAction::UpgradeToWebSocket(sec_websocket_key) => {
-                self.handle_websocket_upgrade_request(
-                    sec_websocket_key);
-            }
-            TcpClientAction::RequestServerShutdown => {
-                debug!("[TCP Client Handler] ({0}): Received ShutdownServer request from handler.", self.address);
-                self.to_server_tx
-                    .send(String::from("ShutdownServer"))
-                    .expect("Error notifying server of shutdown request.");
-            }
+    pub fn finish(mut self, registry: &Registry) {
+        let unsent = self.session.write_buffer().len();
+        if unsent > 0 {
+            warn!(
+                "[TCP Client Handler] ({0}) Client did not take the last {1} bytes in time. Dropping them.",
+                self.session.address(), unsent
+            );
+        }
+        if let Err(error) = registry.deregister(&mut self.stream) {
+            debug!("[TCP Client Handler] ({0}) Error deregistering stream. Error: {1}", self.session.address(), error);
+        }
+        if let Err(error) = self.stream.shutdown(std::net::Shutdown::Both) {
+            debug!("[TCP Client Handler] ({0}) Error shutting down stream. Error: {1}", self.session.address(), error);
+        }
+
+        // Finalize disconnect
+        let client_id = self.session.client_id().to_string();
+        let event = self.session.finish();
+        if self.to_server_tx.send(&client_id, event).is_err() {
+            debug!("[TCP Client Handler] ({0}) Server has gone.", client_id);
         }
     }
 
-    fn handle_message(&mut self, message: String) {
-        self.to_server_tx.send(message).expect("Error notifying server of received message.");
+    /**
+     * Reads everything currently available on the stream.
+     */
+    fn read_stream(&mut self, buffer: &mut [u8]) {
+        // Readiness is edge-triggered, so read until the stream would block
+        while self.session.is_connected() {
+            match self.stream.read(buffer) {
+                Ok(0) => {
+                    self.session.handle_disconnect(DisconnectReason::ClosedByClient);
+                }
+                Ok(size) => {
+                    self.session.handle_request(buffer, &size);
+                }
+                // Nothing more to read for now
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                // Handle error case
+                Err(error) => {
+                    self.session.handle_error(Error::Io(error));
+                }
+            }
+        }
     }
 
     /**
-     * Handles errors reading from the client TCP stream.
+     * Writes as much of the write buffer as the stream will take without blocking.
+     *
+     * Anything left over is sent when the stream next becomes writable.
      */
-    fn handle_error(self: &TcpClientHandler, error: &std::io::Error) {
-        warn!("[TCP Client Handler] ({0}) Error: {1}", &self.address, error);
-        // Inform the server of the error
-        self.to_server_tx
-            .send(String::from("Client Communication Error"))
-            .expect("Error notifying server of client communication error.");
+    fn flush_writes(&mut self, registry: &Registry) {
+        while !self.session.write_buffer().is_empty() {
+            match self.stream.write(self.session.write_buffer()) {
+                Ok(0) => {
+                    let error = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                    self.session.handle_error(Error::Io(error));
+                    self.session.write_buffer().clear();
+                }
+                Ok(size) => {
+                    self.session.write_buffer().drain(0..size);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    self.session.handle_error(Error::Io(error));
+                    self.session.write_buffer().clear();
+                }
+            }
+        }
+
+        // Only ask for writable events while there is something left to write. A disconnected
+        // client keeps its registration until the rest has been written, or until the flush
+        // deadline.
+        let needs_write = !self.session.write_buffer().is_empty();
+        if needs_write && !self.session.is_connected() && self.flush_deadline.is_none() {
+            self.flush_deadline = Some(Instant::now() + FLUSH_TIMEOUT);
+        }
+        if needs_write != self.is_write_registered {
+            let interest = if needs_write {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            match registry.reregister(&mut self.stream, self.token, interest) {
+                Ok(_) => self.is_write_registered = needs_write,
+                Err(error) => {
+                    self.session.handle_error(Error::Io(error));
+                    self.session.write_buffer().clear();
+                }
+            }
+        }
+
+        self.send_events();
     }
 
     /**
-     * Handles a WebSocket upgrade request.
+     * Notifies the server of the session's events.
      */
-    fn handle_websocket_upgrade_request(
-        &mut self,
-        sec_websocket_key: String
-    ) {
-        debug!(
-            "[TCP Client Handler] ({0}) Received request from client to upgrade to WebSocket connection.",
-            self.address
-        );
-        // Build http response to upgrade to websocket
-        let response = response::upgrade_to_websocket(&sec_websocket_key);
-        // Send response to client accepting upgrade request
-        debug!("[TCP Client Handler] ({0}) Sending response accepting request to upgrade to WebSocket connection.", self.address);
-        self.stream
-            .write(response.as_bytes())
-            .expect("Error sending response to upgrade to websocket.");
-
-        // Communicate to server that connection has upgraded to WebSocket
-        self.to_server_tx
-            .send(String::from("Upgrade to WebSocket"))
-            .expect("Error notifying server of WebSocket upgrade.");
-
-        // Replace the request handler with a websocket handler
-        let websocket_handler = WebSocketClientRequestHandler {
-            address: self.address
-        };
-        self.request_handler = Box::new(websocket_handler);
-    }
-
-    
+    fn send_events(&mut self) {
+        for event in self.session.take_events() {
+            if self.to_server_tx.send(self.session.client_id(), event).is_err() {
+                debug!("[TCP Client Handler] ({0}) Server has gone.", self.session.client_id());
+            }
+        }
+    }
 }
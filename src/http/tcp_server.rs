@@ -1,196 +1,423 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::io::Write;
 use std::sync::mpsc::{channel, TryRecvError, Sender, Receiver};
 use log::{debug, warn};
-use super::tcp_client_handler::{TcpClientHandler, TcpClientType};
+use mio::{Events, Interest, Poll, Token, Waker};
+use super::client_session::new_client_id;
+use super::response::{self, HttpResponse};
+use super::server_handle::{ServerHandle, ServerSender};
+use super::server_state::{ClientLink, Effect, Notification, ServerState};
+use super::tcp_client_handler::{ClientEvent, ClientEventSender, ConnectionInfo, TcpClientHandler};
+use super::tcp_server_builder::{HandlerSource, ServerConfig, TcpServerBuilder};
+use super::worker_pool::{Worker, WorkerMessage, WorkerPool};
+use super::websocket_codec::CloseCode;
 use crate::client_handler::ClientHandler;
+use crate::error::Error;
 
-struct TcpClient {
-    pub address: std::net::SocketAddr,
-    pub client_type: TcpClientType,
-    pub is_connected: bool,
-    pub to_client_tx: Sender<Request>,
-    pub from_client_rx: Receiver<String>
+/// How long the server waits before accepting again after an accept error (e.g. out of file
+/// descriptors).
+pub const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Clients are reached through the worker handling their connection.
+impl ClientLink for Worker {
+    fn key(&self) -> usize {
+        self.id()
+    }
+
+    fn send(&self, request: Request) -> Result<(), Error> {
+        Worker::send(self, WorkerMessage::Request(request))?;
+        Ok(())
+    }
+
+    fn send_shared(&self, client_ids: Vec<String>, message: Arc<str>) -> Result<(), Error> {
+        Worker::send(self, WorkerMessage::SendShared { client_ids, message })?;
+        Ok(())
+    }
 }
 
+/// Poll token for the TCP listener.
+const LISTENER: Token = Token(0);
+/// Poll token used to wake the server when a client or the server's handle has sent it something.
+const WAKER: Token = Token(1);
+
+#[derive(Debug)]
 pub struct Request {
     pub client_id: String,
     pub action: Action
 }
 
+/// Events sent from the server to the thread that started it.
+#[derive(Debug, PartialEq)]
+pub enum ServerEvent {
+    /// The server has stopped and all of its clients have disconnected.
+    Stopped,
+}
+
+/// A client in the server's client table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub connection: ConnectionInfo,
+    /// Whether the handler has been told about the client (`on_client_connected`).
+    pub is_connected: bool,
+}
+
+/// How a server's shutdown went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownStatus {
+    /// Every client disconnected within the drain period.
+    Drained,
+    /// The drain period ran out and the given number of connections were closed forcibly.
+    ForceClosed(usize),
+}
+
+impl ShutdownStatus {
+    /// Exit code for a process that ran the server: 0 if every client was drained, 1 if
+    /// connections had to be closed forcibly.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ShutdownStatus::Drained => 0,
+            ShutdownStatus::ForceClosed(_) => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum Action {
     SendMessage(String),
+    SendBinary(Vec<u8>),
+    Close(CloseCode, String),
+    /// Answers the HTTP request the request's client is waiting on (see
+    /// `ClientHandler::on_http_request`).
+    Respond(HttpResponse),
+    /// Sends a text message to every connected WebSocket client. The request's client id is
+    /// not used.
+    Broadcast(String),
+    /// Sends a text message to every connected WebSocket client except the request's client
+    /// (e.g. to relay a client's message to everyone else).
+    BroadcastExcept(String),
+    /// Sends a text message to each of the given clients that is a connected WebSocket client.
+    /// The request's client id is not used.
+    Multicast(Vec<String>, String),
+    /// Adds the request's client to the named room. Only WebSocket clients can join rooms;
+    /// other clients are reported to the handler's `on_error` (`Error::NotWebSocket`).
+    JoinRoom(String),
+    /// Removes the request's client from the named room.
+    LeaveRoom(String),
+    /// Sends a text message (second) to every connected WebSocket client in the named room
+    /// (first). The request's client id is not used.
+    Publish(String, String),
+    /// Asks for the server's current clients, which are sent back on the given channel. The
+    /// request's client id is not used.
+    ListClients(Sender<Vec<ClientInfo>>),
     Stop
 }
 
 /**
  * Represents a TCP server.
+ *
+ * Servers are configured and started with a `TcpServerBuilder` (see `TcpServer::builder`).
  */
 pub struct TcpServer {
-    pub address: String,
-    pub name: String,
-    pub handler: Box<dyn ClientHandler + Send>,
-    pub main_to_server_rx: Receiver<Request>,
-    pub server_to_main_tx: Sender<String>,
+    config: ServerConfig,
+    handler: Box<dyn ClientHandler + Send>,
+    poll: Poll,
+    waker: Arc<Waker>,
+    /// The address the listener is bound to (which has the real port when binding port 0).
+    local_addr: std::net::SocketAddr,
+    requests_rx: Receiver<Request>,
+    events_tx: Sender<ServerEvent>,
 }
 
 impl TcpServer {
     /**
-     * Starts an HTTP server.
+     * Returns a builder for configuring and starting a server.
+     */
+    pub fn builder() -> TcpServerBuilder {
+        TcpServerBuilder::new()
+    }
+
+    /**
+     * Starts an HTTP server on its own thread.
+     *
+     * The listener is bound before the thread starts, so errors binding the address (e.g. the
+     * port is already in use) are returned here.
      */
-    pub fn start(self: TcpServer) {
+    pub(super) fn start(config: ServerConfig, handler: HandlerSource) -> Result<ServerHandle, Error> {
+        // Listener
+        let listener = std::net::TcpListener::bind(&config.address)?;
+        let local_addr = listener.local_addr()?;
+
+        // Set to non-blocking mode
+        listener.set_nonblocking(true)?;
+
+        // Wait for connections and notifications with a poll rather than spinning
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let mut listener = mio::net::TcpListener::from_std(listener);
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        // Start the workers that handle client connections
+        let worker_pool = WorkerPool::new(
+            &config.worker_pool,
+            config.read_buffer_size,
+            config.poll_interval
+        )?;
+
+        // The handle owns the other ends of the server's channels
+        let (requests_tx, requests_rx) = channel::<Request>();
+        let (events_tx, events_rx) = channel::<ServerEvent>();
+        let sender = ServerSender::new(requests_tx, Arc::clone(&waker));
+
+        let handler = match handler {
+            HandlerSource::Handler(handler) => handler,
+            HandlerSource::Factory(factory) => factory(sender.clone()),
+        };
+        let server = TcpServer {
+            config,
+            handler,
+            poll,
+            waker,
+            local_addr,
+            requests_rx,
+            events_tx,
+        };
+
         // Start listener thread
-        std::thread::spawn(move || {
-            // Listener
-            let listener = std::net::TcpListener::bind(&self.address)
-                .expect("[Server] Error binding TCP listener.");
-
-            // Set to non-blocking mode
-            match listener.set_nonblocking(true) {
-                Ok(_) => {}
-                Err(err) => {
-                    warn!("[Server] Could not set non-blocking mode. Error: {0}", err);
-                }
-            };
+        let thread = std::thread::Builder::new()
+            .name(String::from("server"))
+            .spawn(move || server.run(listener, worker_pool))?;
+
+        Ok(ServerHandle::new(sender, events_rx, local_addr, thread))
+    }
 
-            debug!("[Server] ({0}) listening on {1}", &self.name, &self.address);
+    /**
+     * Accepts clients and handles their events until the server is stopped.
+     */
+    fn run(mut self, mut listener: mio::net::TcpListener, mut worker_pool: WorkerPool) -> ShutdownStatus {
+        // Every client reports its events on a single channel
+        let (client_to_server_tx, client_to_server_rx) = channel::<(String, ClientEvent)>();
+        let client_event_sender = ClientEventSender::new(client_to_server_tx, Arc::clone(&self.waker));
+
+        debug!("[Server] ({0}) listening on {1}", &self.config.name, &self.local_addr);
 
-            let mut server_running: bool = true;
-            let mut clients: HashMap<String, TcpClient> = HashMap::new();
+        let mut server_running: bool = true;
+        let mut state: ServerState<Worker> = ServerState::new(&self.config.name, self.config.worker_pool.max_connections);
+        let mut events = Events::with_capacity(128);
+        // When to try accepting again after an accept error
+        let mut accept_retry_at: Option<Instant> = None;
 
-            while server_running {
-                // Check for an incoming connection
+        while server_running {
+            // Sleep until there is a connection to accept or a notification to handle. After an
+            // accept error, connections still waiting won't produce another event, so wake up to
+            // retry them.
+            let retry_in = accept_retry_at.map(|at| at.saturating_duration_since(Instant::now()));
+            let timeout = match (retry_in, self.config.poll_interval) {
+                (Some(retry_in), Some(interval)) => Some(retry_in.min(interval)),
+                (retry_in, interval) => retry_in.or(interval),
+            };
+            if let Err(error) = self.poll.poll(&mut events, timeout) {
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    warn!("[Server] ({0}) Error polling for events. Error: {1}", self.config.name, error);
+                }
+                continue;
+            }
+
+            // Accept every pending connection (readiness is edge-triggered), unless backing off
+            // after an accept error
+            let mut accepting = accept_retry_at.is_none_or(|at| Instant::now() >= at);
+            if accepting {
+                accept_retry_at = None;
+            }
+            while accepting {
                 match listener.accept() {
-                    Ok((stream, address)) => {
-                        let (client_to_server_tx, client_to_server_rx) =
-                            channel::<String>();
-                        let (server_to_client_tx, server_to_client_rx) =
-                            channel::<Request>();
-                        
-                        // Hand off to a new TCP client handler
-                        TcpClientHandler::handle_new_client(
+                    Ok((mut stream, address)) => {
+                        // Refuse connections once the server is full
+                        if state.is_full() {
+                            warn!(
+                                "[Server] ({0}) Refusing connection from {1}. Connection limit ({2}) reached.",
+                                self.config.name, address, self.config.worker_pool.max_connections
+                            );
+                            let refusal = response::service_unavailable().to_bytes();
+                            if let Err(e) = stream.write_all(&refusal) {
+                                debug!("[Server] ({0}) Error refusing connection from {1}. Error: {2}", self.config.name, address, e);
+                            }
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            continue;
+                        }
+
+                        // Hand off to a worker
+                        let client_id = new_client_id();
+                        let handler = TcpClientHandler::new(
                             stream,
+                            client_id.clone(),
                             address,
-                            TcpClientType::Http,
-                            client_to_server_tx,
-                            server_to_client_rx
+                            client_event_sender.clone(),
+                            &self.config.http,
+                            self.config.websocket.clone()
                         );
-
-                        // Define a tracking client (used by the server to passively keep track of the client)
-                        let client = TcpClient {
-                            address: address,
-                            client_type: TcpClientType::Http,
-                            is_connected: false,
-                            to_client_tx: server_to_client_tx,
-                            from_client_rx: client_to_server_rx
+                        let worker = match worker_pool.assign(handler) {
+                            Ok(worker) => worker,
+                            Err(e) => {
+                                warn!(
+                                    "[Server] ({0}) Error handing client {1} to a worker. Error: {2}",
+                                    self.config.name, address, e
+                                );
+                                continue;
+                            }
                         };
 
-                        &clients.insert(address.to_string(), client);
+                        // Track the client (the server only passively keeps track of it)
+                        state.add_client(client_id, address, worker);
                     }
-                    // Handle case where waiting for accept would become blocking
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    // No more connections waiting to be accepted
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => accepting = false,
+                    // The connection was reset before it was accepted, but others may be waiting
+                    Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionAborted
+                        || e.kind() == std::io::ErrorKind::Interrupted => {}
                     Err(e) => {
                         warn!(
                             "[Server] ({0}) Error accepting client connection. Error: {1}",
-                            self.name, e
+                            self.config.name, e
                         );
+                        // Back off rather than spin while the error lasts
+                        accept_retry_at = Some(Instant::now() + ACCEPT_ERROR_BACKOFF);
+                        accepting = false;
                     }
                 }
+            }
 
-                // Check for notifications from clients
-                for (address, client) in clients.iter_mut() {
-                    match client.from_client_rx.try_recv() {
-                        Ok(message) => {
-                            debug!(
-                                "[{0}] ({1}) Received message from client. Message: {2}",
-                                self.name, client.address, message
-                            );
-                            // TODO: This should be a command parser (vs. if blocks)
-                            if message == "Connected" {
-                                // TODO: Mark the client as connected
-                                client.is_connected = true;
-
-                                // Notify the handler (external implementation handler) of the new client
-                                (*self.handler).on_client_connected(address);
-                            }
-                            
-                            else if message == "Upgrade to WebSocket" {
-                                // Upgrade client handler to websocket
-                                client.client_type = TcpClientType::WebSocket;
-                            }
-
-                            else {
-                                // Notify external implementation handler of message from client
-                                (*self.handler).on_message_received(address, &message);
-                            }
-                        }
-                        Err(TryRecvError::Empty) => {}
-                        Err(TryRecvError::Disconnected) => panic!(
-                            "[Server] Error reading from client channel receiver. Channel disconnected."
-                        ),
-                    }
-                }
+            // Check for notifications from clients
+            // (the server holds a sender itself, so the channel never disconnects)
+            while let Ok((client_id, event)) = client_to_server_rx.try_recv() {
+                let effects = state.handle_client_event(client_id, event);
+                self.apply(&mut state, effects);
+            }
 
-                // Check for messages from main thread
-                match self.main_to_server_rx.try_recv() {
+            // Check for messages from main thread
+            loop {
+                match self.requests_rx.try_recv() {
                     Ok(request) => {
-                        match request.action {
-                            Action::SendMessage(_) => {
-                                clients[&request.client_id.to_string()].to_client_tx.send(request).expect("Error sending message to client.");
-                            }
-                            Action::Stop => {
-                                // Stop the server
-                                debug!("[Server {0}] Received request to stop server.", self.name);
-                                server_running = false;
-                            }
+                        let effects = state.handle_request(request);
+                        if self.apply(&mut state, effects) {
+                            server_running = false;
                         }
                     }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => {
-                        panic!("[Server] Error reading from main thread receiver. Channel disconnected")
-                    }
+                    Err(TryRecvError::Empty) => break,
+                    // Every sender has gone, so nothing more will arrive
+                    Err(TryRecvError::Disconnected) => break,
                 }
+            }
+        }
+
+        // Stop accepting connections
+        if let Err(error) = self.poll.registry().deregister(&mut listener) {
+            debug!("[Server] ({0}) Error deregistering TCP listener. Error: {1}", self.config.name, error);
+        }
+        drop(listener);
+
+        // Ask clients to disconnect
+        let effects = state.stop_clients();
+        self.apply(&mut state, effects);
+
+        // Give clients until the end of the drain period to finish disconnecting
+        let drain_deadline = Instant::now() + self.config.drain_timeout;
+        loop {
+            while let Ok((client_id, event)) = client_to_server_rx.try_recv() {
+                let effects = state.handle_client_event(client_id, event);
+                self.apply(&mut state, effects);
+            }
+
+            let now = Instant::now();
+            if state.active_connections() == 0 || now >= drain_deadline {
+                break;
+            }
 
-                std::thread::sleep(std::time::Duration::from_millis(100));
+            debug!("Waiting for client disconnects.");
+            let timeout = match self.config.poll_interval {
+                Some(interval) => interval.min(drain_deadline - now),
+                None => drain_deadline - now,
+            };
+            if let Err(error) = self.poll.poll(&mut events, Some(timeout)) {
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    warn!("[Server] ({0}) Error polling for events. Error: {1}", self.config.name, error);
+                }
             }
+        }
 
-            // Shutdown clients
-            let connected_clients = &clients.len();
-            let mut disconnects = 0;
-            for (address, client) in &clients {
-                debug!(
-                    "[Server] ({0}) Sending disconnect request to client at address {1}.",
-                    self.name, address
+        let status = match state.active_connections() {
+            0 => ShutdownStatus::Drained,
+            remaining => {
+                warn!(
+                    "[Server] ({0}) {1} client(s) did not disconnect in time. Closing their connections.",
+                    self.config.name, remaining
                 );
-                client
-                    .to_client_tx
-                    .send(Request { client_id: address.to_string(), action: Action::Stop})
-                    .expect("[Server] ({0}) Error telling client to disconnect.");
+                ShutdownStatus::ForceClosed(remaining)
             }
+        };
 
-            while connected_clients > &disconnects {
-                for (address, client) in &clients {
-                    match client.from_client_rx.try_recv() {
-                        Ok(message) => {
-                            if message == "Disconnected" {
-                                debug!(
-                                    "[{0}] Client @ {1} disconnected.",
-                                    self.name, address
-                                );
-                                disconnects = disconnects + 1;
-                            }
-                        }
-                        Err(TryRecvError::Empty) => {}
-                        Err(TryRecvError::Disconnected) => {}
-                    }
+        // Stop the workers (closing any connections that remain)
+        worker_pool.shutdown();
+
+        // Report the disconnects of clients closed by the workers
+        while let Ok((client_id, event)) = client_to_server_rx.try_recv() {
+            let effects = state.handle_client_event(client_id, event);
+            self.apply(&mut state, effects);
+        }
+
+        // Indicate to the main thread that this server has stopped
+        if self.events_tx.send(ServerEvent::Stopped).is_err() {
+            warn!("[Server] ({0}) Error sending ServerStopped message to main thread.", self.config.name);
+        }
+
+        status
+    }
+
+    /**
+     * Carries out the effects of a request or client event. Returns whether the server has
+     * been asked to stop.
+     */
+    fn apply(&self, state: &mut ServerState<Worker>, effects: Vec<Effect>) -> bool {
+        let mut stop = false;
+        let mut effects = VecDeque::from(effects);
+        while let Some(effect) = effects.pop_front() {
+            match effect {
+                Effect::Notify(client_id, notification) => {
+                    effects.extend(self.notify(state, &client_id, notification));
                 }
-                debug!("Waiting for client disconnects.");
-                std::thread::sleep(std::time::Duration::from_millis(1000));
+                Effect::Stop => stop = true,
             }
+        }
+        stop
+    }
 
-            // Indicate to the main thread that this server has stopped
-            self.server_to_main_tx
-                .send(String::from("ServerStopped"))
-                .expect("[Server] Error sending ServerStopped message to main thread.");
-        });
+    /**
+     * Notifies the handler about a client, returning the effects of its answer (if any).
+     */
+    fn notify(&self, state: &mut ServerState<Worker>, client_id: &str, notification: Notification) -> Vec<Effect> {
+        let handler = &*self.handler;
+        match notification {
+            Notification::Connected(connection) => handler.on_client_connected(client_id, &connection),
+            Notification::Message(message) => handler.on_message_received(client_id, &message),
+            Notification::Binary(data) => handler.on_binary_message_received(client_id, &data),
+            Notification::Closed(code, reason) => handler.on_websocket_closed(client_id, code, &reason),
+            Notification::UpgradeRequested(connection, request) => {
+                // Let the handler accept or refuse the upgrade
+                let decision = handler.on_websocket_upgrade_requested(client_id, &connection, &request);
+                return state.answer_upgrade(client_id, &request, decision);
+            }
+            Notification::Upgraded(connection, request) => handler.on_websocket_upgraded(client_id, &connection, &request),
+            Notification::HttpRequest(connection, request) => {
+                // Let the handler answer now or later
+                if let Some(response) = handler.on_http_request(client_id, &connection, &request) {
+                    return state.respond(client_id, response);
+                }
+            }
+            Notification::Error(error) => handler.on_error(client_id, &error),
+            Notification::Disconnected(reason) => handler.on_client_disconnected(client_id, &reason),
+        }
+        Vec::new()
     }
 }
@@ -0,0 +1,199 @@
+use std::time::Duration;
+use super::http_request_handler::HttpConfig;
+use super::server_handle::{ServerHandle, ServerSender};
+use super::tcp_server::TcpServer;
+use super::websocket_request_handler::WebSocketConfig;
+use super::worker_pool::WorkerPoolConfig;
+use crate::client_handler::ClientHandler;
+use crate::error::Error;
+
+/// Server settings.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    pub address: String,
+    /// Name used in log messages.
+    pub name: String,
+    /// Size of the buffer each read from a client's stream goes into. Must be greater than 0.
+    pub read_buffer_size: usize,
+    /// Longest the server's event loops sleep without an event. `None` sleeps until there is
+    /// something to do.
+    pub poll_interval: Option<Duration>,
+    /// How long a stopping server waits for its clients to disconnect (e.g. to answer the
+    /// WebSocket close frame) before closing the remaining connections itself.
+    pub drain_timeout: Duration,
+    pub worker_pool: WorkerPoolConfig,
+    pub http: HttpConfig,
+    pub websocket: WebSocketConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: String::from("127.0.0.1:8080"),
+            name: String::from("Server"),
+            read_buffer_size: 4096,
+            poll_interval: None,
+            drain_timeout: Duration::from_secs(10),
+            worker_pool: WorkerPoolConfig::default(),
+            http: HttpConfig::default(),
+            websocket: WebSocketConfig::default(),
+        }
+    }
+}
+
+/// Where the server's handler comes from.
+pub(super) enum HandlerSource {
+    Handler(Box<dyn ClientHandler + Send>),
+    /// Builds the handler once the server's sender exists.
+    Factory(Box<dyn FnOnce(ServerSender) -> Box<dyn ClientHandler + Send>>),
+}
+
+/**
+ * Configures and starts a `TcpServer`.
+ */
+pub struct TcpServerBuilder {
+    config: ServerConfig,
+    handler: Option<HandlerSource>,
+}
+
+impl TcpServerBuilder {
+    pub fn new() -> TcpServerBuilder {
+        TcpServerBuilder {
+            config: ServerConfig::default(),
+            handler: None,
+        }
+    }
+
+    /// Replaces every setting with the given configuration.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn address(mut self, address: &str) -> Self {
+        self.config.address = String::from(address);
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.config.name = String::from(name);
+        self
+    }
+
+    /// Sets the handler notified of client events.
+    pub fn handler<H: ClientHandler + Send + 'static>(mut self, handler: H) -> Self {
+        self.handler = Some(HandlerSource::Handler(Box::new(handler)));
+        self
+    }
+
+    /// Sets the handler notified of client events, built from the server's sender so it can
+    /// reply to clients.
+    pub fn handler_with<H, F>(mut self, factory: F) -> Self
+    where
+        H: ClientHandler + Send + 'static,
+        F: FnOnce(ServerSender) -> H + 'static,
+    {
+        self.handler = Some(HandlerSource::Factory(Box::new(move |sender| {
+            Box::new(factory(sender)) as Box<dyn ClientHandler + Send>
+        })));
+        self
+    }
+
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.config.read_buffer_size = size;
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.poll_interval = interval;
+        self
+    }
+
+    /// How long to wait for clients to disconnect when the server is stopped.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    pub fn worker_count(mut self, count: usize) -> Self {
+        self.config.worker_pool.worker_count = count;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.worker_pool.max_connections = max;
+        self
+    }
+
+    pub fn http_config(mut self, config: HttpConfig) -> Self {
+        self.config.http = config;
+        self
+    }
+
+    /// Largest request body accepted. Larger bodies are answered with `413 Content Too Large`.
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.config.http.max_body_size = size;
+        self
+    }
+
+    /// Accepts (or refuses) WebSocket upgrades. Refused upgrade requests are answered as
+    /// plain HTTP requests.
+    pub fn websockets(mut self, enabled: bool) -> Self {
+        self.config.websocket.enabled = enabled;
+        self
+    }
+
+    pub fn websocket_config(mut self, config: WebSocketConfig) -> Self {
+        self.config.websocket = config;
+        self
+    }
+
+    /// How often WebSocket clients are pinged. `None` disables heartbeats.
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.websocket.ping_interval = interval;
+        self
+    }
+
+    /// How long to wait for a WebSocket client to answer a close frame.
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.config.websocket.close_timeout = timeout;
+        self
+    }
+
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.websocket.max_message_size = size;
+        self
+    }
+
+    pub fn fragment_size(mut self, size: Option<usize>) -> Self {
+        self.config.websocket.fragment_size = size;
+        self
+    }
+
+    /// How long a client has to send a complete request. Slower clients are disconnected.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.http.request_timeout = timeout;
+        self
+    }
+
+    /**
+     * Binds the server's address and starts the server on its own thread.
+     *
+     * Returns an error if no handler was given, a setting is invalid or the address can't be
+     * bound.
+     */
+    pub fn start(self) -> Result<ServerHandle, Error> {
+        let handler = self.handler.ok_or(Error::MissingHandler)?;
+        if self.config.read_buffer_size == 0 {
+            return Err(Error::InvalidConfig(String::from("read_buffer_size must be greater than 0")));
+        }
+        TcpServer::start(self.config, handler)
+    }
+}
+
+impl Default for TcpServerBuilder {
+    fn default() -> Self {
+        TcpServerBuilder::new()
+    }
+}
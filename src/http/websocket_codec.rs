@@ -0,0 +1,599 @@
+/// WebSocket frame opcodes.
+///
+/// Opcodes: https://tools.ietf.org/html/rfc6455#section-5.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    pub fn from_u8(value: u8) -> Opcode {
+        match value {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xA => Opcode::Pong,
+            other => Opcode::Reserved(other),
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+            Opcode::Reserved(other) => other,
+        }
+    }
+
+    /// Control frames (close, ping and pong) have the high bit of the opcode set.
+    pub fn is_control(self) -> bool {
+        self.as_u8() & 0b1000 != 0
+    }
+}
+
+/// WebSocket close status codes.
+///
+/// Status codes: https://tools.ietf.org/html/rfc6455#section-7.4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    NoStatus,
+    Abnormal,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    InternalError,
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn from_u16(value: u16) -> CloseCode {
+        match value {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1011 => CloseCode::InternalError,
+            other => CloseCode::Other(other),
+        }
+    }
+
+    pub fn as_u16(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::InternalError => 1011,
+            CloseCode::Other(other) => other,
+        }
+    }
+
+    /// Codes 1005 and 1006 are reserved for reporting and must never be sent in a close frame.
+    pub fn is_sendable(self) -> bool {
+        !matches!(self, CloseCode::NoStatus | CloseCode::Abnormal)
+    }
+
+    /// Whether a close frame may carry the code: one of those defined for close frames
+    /// (1000-1003 and 1007-1014) or one set aside for libraries and applications (3000-4999).
+    pub fn is_valid(self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+/// A single WebSocket frame.
+///
+/// WebSocket frame layout: https://tools.ietf.org/html/rfc6455#section-5.2
+pub struct WebSocketFrame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// The fixed and extended header fields of a WebSocket frame.
+pub struct WebSocketFrameHeader {
+    pub fin: bool,
+    pub reserved: u8,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    pub payload_len: u64,
+    pub header_len: usize,
+}
+
+/// The frames decoded from the bytes read so far.
+pub struct DecodedFrames {
+    /// The complete, valid frames, in the order they arrived.
+    pub frames: Vec<WebSocketFrame>,
+    /// The close code to fail the connection with, and the rule broken, if a frame after
+    /// `frames` violates the protocol. Nothing after it is decoded.
+    pub error: Option<(CloseCode, &'static str)>,
+}
+
+/// Incrementally decodes WebSocket frames from a TCP byte stream.
+///
+/// Bytes are accumulated across reads so that frames split over several reads, or several
+/// frames arriving in a single read, are decoded correctly. Any trailing partial frame is
+/// kept until the rest of it arrives.
+pub struct WebSocketFrameDecoder {
+    buffer: Vec<u8>,
+    max_payload_size: Option<u64>,
+}
+
+impl WebSocketFrameDecoder {
+    pub fn new() -> WebSocketFrameDecoder {
+        WebSocketFrameDecoder { buffer: Vec::new(), max_payload_size: None }
+    }
+
+    /// Creates a decoder that rejects frames with a payload larger than `max_payload_size`.
+    pub fn with_max_payload_size(max_payload_size: u64) -> WebSocketFrameDecoder {
+        WebSocketFrameDecoder { buffer: Vec::new(), max_payload_size: Some(max_payload_size) }
+    }
+
+    /// Appends bytes read from the stream and returns every frame that is now complete.
+    ///
+    /// If the client violates the protocol, the frames before the violation are still
+    /// returned, along with the close code the connection should be closed with.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes read from the stream.
+    pub fn decode(&mut self, data: &[u8]) -> DecodedFrames {
+        self.buffer.extend_from_slice(data);
+
+        let mut frames: Vec<WebSocketFrame> = Vec::new();
+        let mut error: Option<(CloseCode, &'static str)> = None;
+        let mut consumed: usize = 0;
+        while let Some(header) = parse_websocket_frame_header(&self.buffer[consumed..]) {
+            if let Err(violation) = self.validate(&header) {
+                error = Some(violation);
+                break;
+            }
+
+            // Wait until the whole payload has arrived
+            let available = (self.buffer.len() - consumed - header.header_len) as u64;
+            if available < header.payload_len {
+                break;
+            }
+
+            let start = consumed + header.header_len;
+            let end = start + header.payload_len as usize;
+            frames.push(WebSocketFrame {
+                fin: header.fin,
+                opcode: header.opcode,
+                payload: unmask(&self.buffer[start..end], header.mask),
+            });
+            consumed = end;
+        }
+
+        // Keep any partial frame for the next read
+        self.buffer.drain(0..consumed);
+
+        DecodedFrames { frames, error }
+    }
+
+    /// Checks a frame header against the rules a server enforces on client frames.
+    fn validate(&self, header: &WebSocketFrameHeader) -> Result<(), (CloseCode, &'static str)> {
+        // No extensions are negotiated, so reserved bits must be clear
+        if header.reserved != 0 {
+            return Err((CloseCode::ProtocolError, "Reserved bits set"));
+        }
+        if let Opcode::Reserved(_) = header.opcode {
+            return Err((CloseCode::ProtocolError, "Reserved opcode"));
+        }
+        // The most significant bit of a 64-bit length must be 0
+        if header.payload_len > i64::MAX as u64 {
+            return Err((CloseCode::ProtocolError, "Invalid payload length"));
+        }
+        // Control frames must not be fragmented and carry at most 125 bytes
+        if header.opcode.is_control() && !header.fin {
+            return Err((CloseCode::ProtocolError, "Fragmented control frame"));
+        }
+        if header.opcode.is_control() && header.payload_len > 125 {
+            return Err((CloseCode::ProtocolError, "Oversized control frame"));
+        }
+        // Frames sent from client to server are always masked
+        if header.mask.is_none() {
+            return Err((CloseCode::ProtocolError, "Unmasked frame"));
+        }
+        if let Some(max_payload_size) = self.max_payload_size {
+            if header.payload_len > max_payload_size {
+                return Err((CloseCode::MessageTooBig, "Frame too big"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for WebSocketFrameDecoder {
+    fn default() -> Self {
+        WebSocketFrameDecoder::new()
+    }
+}
+
+/// Parses the header of a websocket frame from the start of a buffer.
+///
+/// Returns `None` if the buffer does not yet hold the complete header.
+///
+/// WebSocket frame layout: https://tools.ietf.org/html/rfc6455#section-5.2
+pub fn parse_websocket_frame_header(content: &[u8]) -> Option<WebSocketFrameHeader> {
+    if content.len() < 2 {
+        return None;
+    }
+
+    let fin: bool = (content[0] & 0b10000000) != 0; // Bit 0 has fin bit
+    let reserved: u8 = (content[0] & 0b01110000) >> 4; // Bits 1 - 3 contain reserved flags 1 - 3
+    let opcode = Opcode::from_u8(content[0] & 0b00001111); // Bits 4 - 7 contain opcode (1-3 are reserved)
+    let mask_bit: bool = (content[1] & 0b10000000) != 0; // Bit 8 contains mask flag
+    let payload_len = content[1] & 0b01111111; // Bits 9 - 15 contain payload length
+
+    // Lengths of 126 and 127 indicate a 16-bit or 64-bit extended payload length
+    let (payload_len, mut header_len): (u64, usize) = match payload_len {
+        126 => {
+            if content.len() < 4 {
+                return None;
+            }
+            (u16::from_be_bytes([content[2], content[3]]) as u64, 4)
+        }
+        127 => {
+            if content.len() < 10 {
+                return None;
+            }
+            let mut extended = [0u8; 8];
+            extended.copy_from_slice(&content[2..10]);
+            (u64::from_be_bytes(extended), 10)
+        }
+        len => (len as u64, 2),
+    };
+
+    // Next 32-bits define the mask (client frames are always masked)
+    let mut mask: Option<[u8; 4]> = None;
+    if mask_bit {
+        if content.len() < header_len + 4 {
+            return None;
+        }
+        let mut key = [0u8; 4];
+        key.copy_from_slice(&content[header_len..header_len + 4]);
+        mask = Some(key);
+        header_len += 4;
+    }
+
+    Some(WebSocketFrameHeader { fin, reserved, opcode, mask, payload_len, header_len })
+}
+
+/// Decodes payload content (XOR payload bits with mask bits).
+fn unmask(payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    match mask {
+        Some(mask) => payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]) // 32 mask bits are used repeatedly
+            .collect(),
+        None => payload.to_vec(),
+    }
+}
+
+/// Returns the status code and reason carried by a close frame.
+///
+/// A close frame without a body reports `CloseCode::NoStatus`. Returns `None` if the body is
+/// malformed (a lone byte, a status code that is not valid in a close frame or a reason that is
+/// not valid UTF-8).
+///
+/// Close frames: https://tools.ietf.org/html/rfc6455#section-5.5.1
+pub fn parse_close_payload(payload: &[u8]) -> Option<(CloseCode, String)> {
+    match payload.len() {
+        0 => Some((CloseCode::NoStatus, String::new())),
+        1 => None,
+        _ => {
+            let code = CloseCode::from_u16(u16::from_be_bytes([payload[0], payload[1]]));
+            if !code.is_valid() {
+                return None;
+            }
+            let reason = std::str::from_utf8(&payload[2..]).ok()?;
+            Some((code, String::from(reason)))
+        }
+    }
+}
+
+/// Returns a byte-array containing a close frame.
+///
+/// # Arguments
+///
+/// * `code` - The close status code.
+/// * `reason` - A short human readable reason (truncated to fit the 125 byte control frame limit).
+pub fn build_close_frame(code: CloseCode, reason: &str) -> Vec<u8> {
+    if !code.is_sendable() {
+        return build_websocket_frame(Opcode::Close, true, &[]);
+    }
+
+    let mut payload: Vec<u8> = Vec::with_capacity(2 + reason.len());
+    payload.extend_from_slice(&code.as_u16().to_be_bytes());
+
+    // Never split a UTF-8 character when truncating the reason
+    let mut reason_len = std::cmp::min(reason.len(), 123);
+    while !reason.is_char_boundary(reason_len) {
+        reason_len -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..reason_len]);
+
+    build_websocket_frame(Opcode::Close, true, &payload)
+}
+
+/// Returns a byte-array containing a websocket frame.
+/// 
+/// WebSocket frame layout: https://tools.ietf.org/html/rfc6455#section-5.2
+/// 
+/// # Arguments
+/// 
+/// * `opcode` - The opcode of the frame.
+/// * `fin` - Whether this is the final frame of a message.
+/// * `payload` - The content of the websocket frame.
+pub fn build_websocket_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(payload.len() + 10);
+
+    // Set fin bit and op code
+    let fin_bit: u8 = if fin { 0b1000_0000 } else { 0 };
+    result.push(fin_bit | opcode.as_u8());
+
+    // Set payload length (server frames are never masked)
+    if payload.len() < 126 {
+        result.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        result.push(126);
+        result.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        result.push(127);
+        result.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    result.extend_from_slice(payload);
+
+    result
+}
+
+/// Returns a byte-array containing a complete websocket message.
+///
+/// If a fragment size is given and the payload is larger than it, the message is split into
+/// an initial frame followed by continuation frames, with the fin bit set on the last one.
+///
+/// Fragmentation: https://tools.ietf.org/html/rfc6455#section-5.4
+///
+/// # Arguments
+///
+/// * `opcode` - The opcode of the message (text or binary).
+/// * `payload` - The content of the message.
+/// * `fragment_size` - The maximum payload size of each frame (if any).
+pub fn build_websocket_message(
+    opcode: Opcode,
+    payload: &[u8],
+    fragment_size: Option<usize>,
+) -> Vec<u8> {
+    let fragment_size = match fragment_size {
+        Some(size) if size > 0 && payload.len() > size => size,
+        _ => return build_websocket_frame(opcode, true, payload),
+    };
+
+    let fragment_count = payload.len().div_ceil(fragment_size);
+    let mut result: Vec<u8> = Vec::with_capacity(payload.len() + 10 * fragment_count);
+    for (i, fragment) in payload.chunks(fragment_size).enumerate() {
+        let frame_opcode = if i == 0 { opcode } else { Opcode::Continuation };
+        result.extend(build_websocket_frame(frame_opcode, i + 1 == fragment_count, fragment));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    /// Builds a masked frame, as a client sends it.
+    fn client_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = build_websocket_frame(opcode, fin, payload);
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0b1000_0000;
+        frame.truncate(header_len);
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    fn decode_all(decoder: &mut WebSocketFrameDecoder, data: &[u8]) -> Vec<WebSocketFrame> {
+        let decoded = decoder.decode(data);
+        assert_eq!(decoded.error, None);
+        decoded.frames
+    }
+
+    #[test]
+    fn encodes_lengths_at_the_extended_length_boundaries() {
+        let cases: [(usize, &[u8]); 5] = [
+            (0, &[0]),
+            (125, &[125]),
+            (126, &[126, 0, 126]),
+            (65535, &[126, 0xFF, 0xFF]),
+            (65536, &[127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ];
+        for (length, encoded) in cases.iter() {
+            let frame = build_websocket_frame(Opcode::Binary, true, &vec![7u8; *length]);
+            assert_eq!(frame[0], 0b1000_0010);
+            assert_eq!(&frame[1..1 + encoded.len()], *encoded, "length {}", length);
+            assert_eq!(frame.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[test]
+    fn decodes_lengths_at_the_extended_length_boundaries() {
+        for length in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let frames = decode_all(&mut WebSocketFrameDecoder::new(), &client_frame(Opcode::Binary, true, &payload));
+            assert_eq!(frames.len(), 1, "length {}", length);
+            assert_eq!(frames[0].payload, payload, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn decodes_frames_split_at_every_byte() {
+        for length in [5, 126, 65536] {
+            let payload = vec![b'a'; length];
+            let frame = client_frame(Opcode::Text, true, &payload);
+            for split in 1..frame.len().min(20) {
+                let mut decoder = WebSocketFrameDecoder::new();
+                assert!(decode_all(&mut decoder, &frame[..split]).is_empty(), "split at {}", split);
+                let frames = decode_all(&mut decoder, &frame[split..]);
+                assert_eq!(frames.len(), 1, "split at {}", split);
+                assert_eq!(frames[0].payload, payload, "split at {}", split);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_a_frame_fed_one_byte_at_a_time() {
+        let frame = client_frame(Opcode::Text, true, b"Hello");
+        let mut decoder = WebSocketFrameDecoder::new();
+        let mut frames = Vec::new();
+        for byte in &frame {
+            frames.extend(decode_all(&mut decoder, &[*byte]));
+        }
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload, b"Hello");
+    }
+
+    #[test]
+    fn decodes_coalesced_frames() {
+        let mut data = client_frame(Opcode::Text, false, b"Hel");
+        data.extend(client_frame(Opcode::Ping, true, b""));
+        data.extend(client_frame(Opcode::Continuation, true, b"lo"));
+        // Followed by the start of another frame
+        let next = client_frame(Opcode::Binary, true, &[1, 2, 3]);
+        data.extend_from_slice(&next[..3]);
+
+        let mut decoder = WebSocketFrameDecoder::new();
+        let frames = decode_all(&mut decoder, &data);
+        let decoded: Vec<(bool, Opcode, &[u8])> = frames
+            .iter()
+            .map(|frame| (frame.fin, frame.opcode, frame.payload.as_slice()))
+            .collect();
+        assert_eq!(
+            decoded,
+            [(false, Opcode::Text, &b"Hel"[..]), (true, Opcode::Ping, &b""[..]), (true, Opcode::Continuation, &b"lo"[..])]
+        );
+
+        let frames = decode_all(&mut decoder, &next[3..]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload, [1, 2, 3]);
+    }
+
+    #[test]
+    fn unmasks_payloads() {
+        let frame = client_frame(Opcode::Text, true, b"Hello");
+        // The payload is sent masked
+        assert_eq!(&frame[6..], [b'H' ^ 0x12, b'e' ^ 0x34, b'l' ^ 0x56, b'l' ^ 0x78, b'o' ^ 0x12]);
+
+        let header = parse_websocket_frame_header(&frame).unwrap();
+        assert_eq!(header.mask, Some(MASK));
+        assert_eq!(header.header_len, 6);
+        let frames = decode_all(&mut WebSocketFrameDecoder::new(), &frame);
+        assert_eq!(frames[0].payload, b"Hello");
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let decoded = WebSocketFrameDecoder::new().decode(&build_websocket_frame(Opcode::Text, true, b"Hello"));
+        assert!(decoded.frames.is_empty());
+        assert_eq!(decoded.error, Some((CloseCode::ProtocolError, "Unmasked frame")));
+    }
+
+    #[test]
+    fn keeps_the_frames_before_an_invalid_one() {
+        let mut data = client_frame(Opcode::Text, true, b"Hello");
+        data.extend(client_frame(Opcode::Reserved(0x3), true, b""));
+        data.extend(client_frame(Opcode::Text, true, b"Never read"));
+
+        let decoded = WebSocketFrameDecoder::new().decode(&data);
+        assert_eq!(decoded.frames.len(), 1);
+        assert_eq!(decoded.frames[0].payload, b"Hello");
+        assert_eq!(decoded.error, Some((CloseCode::ProtocolError, "Reserved opcode")));
+    }
+
+    #[test]
+    fn rejects_64_bit_lengths_with_the_most_significant_bit_set() {
+        let mut frame = vec![0b1000_0010, 0b1000_0000 | 127, 0x80, 0, 0, 0, 0, 0, 0, 1];
+        frame.extend_from_slice(&MASK);
+
+        let decoded = WebSocketFrameDecoder::new().decode(&frame);
+        assert_eq!(decoded.error, Some((CloseCode::ProtocolError, "Invalid payload length")));
+    }
+
+    #[test]
+    fn rejects_payloads_over_the_size_limit() {
+        let mut decoder = WebSocketFrameDecoder::with_max_payload_size(125);
+        assert_eq!(decode_all(&mut decoder, &client_frame(Opcode::Binary, true, &[0; 125])).len(), 1);
+
+        // Rejected as soon as the header arrives
+        let frame = client_frame(Opcode::Binary, true, &[0; 126]);
+        assert_eq!(decoder.decode(&frame[..8]).error, Some((CloseCode::MessageTooBig, "Frame too big")));
+    }
+
+    #[test]
+    fn rejects_fragmented_or_oversized_control_frames() {
+        let decoded = WebSocketFrameDecoder::new().decode(&client_frame(Opcode::Ping, false, b""));
+        assert_eq!(decoded.error, Some((CloseCode::ProtocolError, "Fragmented control frame")));
+        let decoded = WebSocketFrameDecoder::new().decode(&client_frame(Opcode::Ping, true, &[0; 126]));
+        assert_eq!(decoded.error, Some((CloseCode::ProtocolError, "Oversized control frame")));
+    }
+
+    #[test]
+    fn parses_close_payloads() {
+        assert_eq!(parse_close_payload(&[]), Some((CloseCode::NoStatus, String::new())));
+        assert_eq!(parse_close_payload(&[0x03, 0xE8]), Some((CloseCode::Normal, String::new())));
+        assert_eq!(parse_close_payload(&[0x03, 0xE9, b'B', b'y', b'e']), Some((CloseCode::GoingAway, String::from("Bye"))));
+        assert_eq!(parse_close_payload(&[0x03]), None);
+        assert_eq!(parse_close_payload(&[0x03, 0xE8, 0xFF]), None);
+    }
+
+    #[test]
+    fn accepts_only_close_codes_valid_in_a_close_frame() {
+        for code in [1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 1012, 1013, 1014, 3000, 4000, 4999] {
+            let payload = u16::to_be_bytes(code);
+            assert_eq!(parse_close_payload(&payload), Some((CloseCode::from_u16(code), String::new())), "code {}", code);
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2000, 2999, 5000, 65535] {
+            assert_eq!(parse_close_payload(&u16::to_be_bytes(code)), None, "code {}", code);
+        }
+    }
+
+    #[test]
+    fn fragments_messages() {
+        let message = build_websocket_message(Opcode::Text, b"Hello", Some(2));
+        assert_eq!(
+            message,
+            [0b0000_0001, 2, b'H', b'e', 0b0000_0000, 2, b'l', b'l', 0b1000_0000, 1, b'o']
+        );
+    }
+}
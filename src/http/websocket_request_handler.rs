@@ -1,12 +1,226 @@
 use std::io::Write;
-use log::{debug};
-use super::tcp_client_handler::{TcpClientAction, TcpClientRequestHandler};
+use std::time::{Duration, Instant};
+use log::{debug, trace, warn};
+use super::tcp_client_handler::{DisconnectReason, TcpClientAction, TcpClientRequestHandler};
+use super::websocket_codec::{
+    build_close_frame, build_websocket_frame, build_websocket_message, parse_close_payload,
+    CloseCode, Opcode, WebSocketFrame, WebSocketFrameDecoder,
+};
+use crate::error::Error;
+
+/// WebSocket connection settings.
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    /// Whether WebSocket upgrade requests are accepted.
+    pub enabled: bool,
+    /// Maximum payload size of outbound frames. Larger messages are sent as continuation
+    /// frames. `None` sends every message as a single frame.
+    pub fragment_size: Option<usize>,
+    /// How often to ping the client. `None` disables heartbeats.
+    pub ping_interval: Option<Duration>,
+    /// Number of consecutive pings that may go unanswered before the client is dropped.
+    pub max_missed_pongs: u32,
+    /// Largest message (after reassembly) accepted from a client. Larger messages close the
+    /// connection with status 1009.
+    pub max_message_size: usize,
+    /// How long to wait for the client to answer a close frame before dropping the connection.
+    pub close_timeout: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            enabled: true,
+            fragment_size: None,
+            ping_interval: Some(Duration::from_secs(30)),
+            max_missed_pongs: 2,
+            max_message_size: 16 * 1024 * 1024,
+            close_timeout: Duration::from_secs(5),
+        }
+    }
+}
 
 pub struct WebSocketClientRequestHandler {
     /**
      * IP address of connected client.
      */
     pub address: std::net::SocketAddr,
+    /**
+     * Decoder holding any partially received frame.
+     */
+    pub decoder: WebSocketFrameDecoder,
+    /**
+     * Connection settings.
+     */
+    pub config: WebSocketConfig,
+    /**
+     * Opcode and payload of a fragmented message that is still being received.
+     */
+    pub fragmented_message: Option<(Opcode, Vec<u8>)>,
+    /**
+     * When the last heartbeat ping was sent.
+     */
+    pub last_ping: Instant,
+    /**
+     * Number of pings sent since the last pong was received.
+     */
+    pub missed_pongs: u32,
+    /**
+     * When the server sent its close frame (if it has).
+     */
+    pub close_sent: Option<Instant>,
+}
+
+impl WebSocketClientRequestHandler {
+    pub fn new(address: std::net::SocketAddr, config: WebSocketConfig) -> WebSocketClientRequestHandler {
+        WebSocketClientRequestHandler {
+            address,
+            decoder: WebSocketFrameDecoder::with_max_payload_size(config.max_message_size as u64),
+            config,
+            fragmented_message: None,
+            last_ping: Instant::now(),
+            missed_pongs: 0,
+            close_sent: None,
+        }
+    }
+
+    /**
+     * Handles a single decoded frame, reassembling fragmented messages.
+     */
+    fn handle_frame(&mut self, frame: WebSocketFrame, actions: &mut Vec<TcpClientAction>) {
+        // Control frames may be interleaved with the fragments of a message
+        if frame.opcode.is_control() {
+            return self.handle_control_frame(frame, actions);
+        }
+
+        match frame.opcode {
+            Opcode::Text | Opcode::Binary => {
+                if self.fragmented_message.is_some() {
+                    warn!(
+                        "[WebSocket Client] ({0}) Received a new message before the previous fragmented message completed.",
+                        &self.address
+                    );
+                    return self.fail(CloseCode::ProtocolError, "Expected continuation frame", actions);
+                }
+                if frame.fin {
+                    return self.handle_message(frame.opcode, frame.payload, actions);
+                }
+                self.fragmented_message = Some((frame.opcode, frame.payload));
+            }
+            Opcode::Continuation => {
+                let (opcode, mut payload) = match self.fragmented_message.take() {
+                    Some(message) => message,
+                    None => {
+                        warn!(
+                            "[WebSocket Client] ({0}) Received a continuation frame with no message to continue.",
+                            &self.address
+                        );
+                        return self.fail(CloseCode::ProtocolError, "Unexpected continuation frame", actions);
+                    }
+                };
+                if payload.len() + frame.payload.len() > self.config.max_message_size {
+                    return self.fail(CloseCode::MessageTooBig, "Message too big", actions);
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    return self.handle_message(opcode, payload, actions);
+                }
+                self.fragmented_message = Some((opcode, payload));
+            }
+            opcode => {
+                warn!(
+                    "[WebSocket Client] ({0}) Received frame with unsupported opcode {1:?}.",
+                    &self.address, opcode
+                );
+                self.fail(CloseCode::ProtocolError, "Unsupported opcode", actions);
+            }
+        }
+    }
+
+    /**
+     * Handles a control frame (close, ping or pong).
+     */
+    fn handle_control_frame(&mut self, frame: WebSocketFrame, actions: &mut Vec<TcpClientAction>) {
+        debug!(
+            "[WebSocket Client] ({0}) Received control frame {1:?}.",
+            &self.address, frame.opcode
+        );
+
+        match frame.opcode {
+            // Reply to pings with a pong carrying the same payload
+            Opcode::Ping => actions.push(TcpClientAction::SendData(
+                build_websocket_frame(Opcode::Pong, true, &frame.payload))),
+            // The client is alive
+            Opcode::Pong => {
+                self.missed_pongs = 0;
+            }
+            Opcode::Close => self.handle_close_frame(&frame.payload, actions),
+            _ => {}
+        }
+    }
+
+    /**
+     * Handles a close frame from the client.
+     *
+     * If the server has not already sent a close frame, the client's status code is echoed
+     * back before the connection is closed.
+     */
+    fn handle_close_frame(&mut self, payload: &[u8], actions: &mut Vec<TcpClientAction>) {
+        let (code, reason) = match parse_close_payload(payload) {
+            Some(close) => close,
+            None => return self.fail(CloseCode::ProtocolError, "Malformed close frame", actions),
+        };
+        debug!(
+            "[WebSocket Client] ({0}) Client closed connection. Code: {1}, Reason: {2}",
+            &self.address, code.as_u16(), reason
+        );
+
+        if self.close_sent.is_none() {
+            self.close_sent = Some(Instant::now());
+            actions.push(TcpClientAction::SendData(build_close_frame(code, "")));
+        }
+        actions.push(TcpClientAction::HandleClose(code, reason));
+        actions.push(TcpClientAction::CloseConnection(DisconnectReason::ClosedByClient));
+    }
+
+    /**
+     * Handles a complete (possibly reassembled) message.
+     */
+    fn handle_message(&mut self, opcode: Opcode, payload: Vec<u8>, actions: &mut Vec<TcpClientAction>) {
+        // Binary messages are passed through untouched
+        if opcode == Opcode::Binary {
+            debug!("Received: {0} bytes of binary data", payload.len());
+            return actions.push(TcpClientAction::HandleBinaryMessage(payload));
+        }
+
+        // Convert decoded payload into string
+        let content = match String::from_utf8(payload) {
+            Ok(content) => content,
+            Err(error) => {
+                warn!(
+                    "[WebSocket Client] ({0}) Received text message that is not valid UTF-8: {1}",
+                    &self.address, error
+                );
+                return self.fail(CloseCode::InvalidPayload, "Invalid UTF-8", actions);
+            }
+        };
+        debug!("Received: {0}", content);
+        actions.push(TcpClientAction::HandleMessage(content));
+    }
+
+    /**
+     * Fails the connection: sends a close frame with the given code and drops the client,
+     * reporting the violation as a protocol error.
+     */
+    fn fail(&mut self, code: CloseCode, reason: &str, actions: &mut Vec<TcpClientAction>) {
+        warn!(
+            "[WebSocket Client] ({0}) Closing connection. Code: {1}, Reason: {2}",
+            &self.address, code.as_u16(), reason
+        );
+        actions.extend(self.close_connection(code, reason));
+        actions.push(TcpClientAction::HandleClose(code, String::from(reason)));
+        actions.push(TcpClientAction::HandleError(Error::Protocol(String::from(reason))));
+    }
 }
 
 impl TcpClientRequestHandler for WebSocketClientRequestHandler {
@@ -14,109 +228,247 @@ impl TcpClientRequestHandler for WebSocketClientRequestHandler {
      * Handles an WebSocket client request.
      */
     fn handle_request(
-        self: &WebSocketClientRequestHandler, 
-        _stream: &std::net::TcpStream,
+        &mut self, 
+        _stream: &mut dyn Write,
         data: &[u8], 
-        num_bytes: &usize) -> TcpClientAction {
+        num_bytes: &usize) -> Vec<TcpClientAction> {
         debug!(
             "[WebSocket Client] ({0}) Received {1} bytes.",
             &self.address, num_bytes
         );
 
         // TODO: Move the following (commented) code to a trait for log display
-        for i in 0..*num_bytes {
-            println!("Byte {0: >2} is {1: >3}: {1:0>8b}", i, data[i]);
+        for (i, byte) in data[0..*num_bytes].iter().enumerate() {
+            trace!("Byte {0: >2} is {1: >3}: {1:0>8b}", i, byte);
         }
         // Print the data as a base64 encoded string
-        println!("Base64: {}", base64::encode(&data[0..*num_bytes]));
+        trace!("Base64: {}", base64::encode(&data[0..*num_bytes]));
         
-        let content = parse_websocket_frame(data, num_bytes);
-        debug!("Received: {0}", content);
-        
-        // TODO: This should be a command-parser (vs. multiple if statement blocks)
-        // Check for ShutdownServer command
-        if content == "ShutdownServer" {
-            return TcpClientAction::RequestServerShutdown;
+        let mut actions: Vec<TcpClientAction> = Vec::new();
+        let decoded = self.decoder.decode(&data[0..*num_bytes]);
+        for frame in decoded.frames {
+            debug!(
+                "[WebSocket Client] ({0}) Decoded frame. Fin: {1}, Opcode: {2:?}, Length: {3}",
+                &self.address, frame.fin, frame.opcode, frame.payload.len()
+            );
+
+            self.handle_frame(frame, &mut actions);
+
+            // Nothing after a close is processed
+            if actions.iter().any(|action| {
+                matches!(action, TcpClientAction::CloseConnection(_) | TcpClientAction::HandleError(_))
+            }) {
+                return actions;
+            }
+        }
+
+        // The frames before an invalid one are still handled
+        if let Some((code, reason)) = decoded.error {
+            self.fail(code, reason, &mut actions);
         }
 
-        return TcpClientAction::HandleMessage(content);
+        actions
     }
 
+    /**
+     * Sends a text message, unless the server has already sent its close frame (no data may
+     * follow a close frame).
+     */
     fn send_response(
-        self: &WebSocketClientRequestHandler, 
-        stream: &mut std::net::TcpStream,
-        message: String) {
-        // Build websocket frame
-        let data: Vec<u8> = build_websocket_frame(&message.to_string());
+        &self, 
+        stream: &mut dyn Write,
+        message: &str) -> std::io::Result<()> {
+        if self.close_sent.is_some() {
+            debug!("[WebSocket Client] ({0}) Dropping message sent after the close frame.", &self.address);
+            return Ok(());
+        }
 
-        for i in 0..data.len() {
-            println!("Byte {0: >2} is {1: >3}: {1:0>8b}", i, data[i]);
+        // Build websocket frame(s)
+        let data: Vec<u8> = build_websocket_message(
+            Opcode::Text, message.as_bytes(), self.config.fragment_size);
+
+        for (i, byte) in data.iter().enumerate() {
+            trace!("Byte {0: >2} is {1: >3}: {1:0>8b}", i, byte);
         }
 
-        stream.write(&data).expect("Error writing message {0} to stream.");
+        stream.write_all(&data)
     }
-}
 
-/// Returns the contents of a websocket frame.
-/// 
-/// WebSocket frame layout: https://tools.ietf.org/html/rfc6455#section-5.2
-fn parse_websocket_frame(content: &[u8], size: &usize) -> String {
-    let _fin_bit: bool = (content[0] & 0b10000000) != 0; // Bit 0 has fin bit
-    let _rsv1: bool = (content[0] & 0b01000000) != 0; // Bit 1 contains reserved flag 1
-    let _rsv2: bool = (content[0] & 0b00100000) != 0; // Bit 2 contains reserved flag 2
-    let _rsv3: bool = (content[0] & 0b00010000) != 0; // Bit 3 contains reserved flag 3
-    let _opcode = (content[0] & 0b00001111) as u8; // Bits 4 - 7 contain opcode (1-3 are reserved)
-    let _mask_bit: bool = (content[1] & 0b10000000) != 0; // Bit 8 contains mask flag
-    let _payload_len = (content[1] & 0b01111111) as u8; // Bits 9 - 15 contain payload length
-
-    // TODO: Handle case where payload length > 126
-
-    // Next 32-bits define the mask (in short payload case)
-    let mask:[u8; 4] = [content[2], content[3], content[4], content[5]];
-
-    // Decode payload content (XOR payload bits with mask bits)
-    let mut decoded: Vec<u8> = Vec::new();
-    for i in 0..size-6 {
-        decoded.push(content[6+i] ^ mask[i % 4]); // 32 mask bits are used repeatedly
-    }
-
-    // Convert decoded payload into string
-    let result = std::str::from_utf8(&decoded).expect("Error decoding websocket payload.");
-    
-    return String::from(result);
+    /**
+     * Sends a binary message, unless the server has already sent its close frame.
+     */
+    fn send_binary_response(
+        &self,
+        stream: &mut dyn Write,
+        data: Vec<u8>) -> std::io::Result<()> {
+        if self.close_sent.is_some() {
+            debug!("[WebSocket Client] ({0}) Dropping binary message sent after the close frame.", &self.address);
+            return Ok(());
+        }
+
+        // Build websocket frame(s)
+        let data: Vec<u8> = build_websocket_message(
+            Opcode::Binary, &data, self.config.fragment_size);
+
+        stream.write_all(&data)
+    }
+
+    /**
+     * Starts the closing handshake by sending a close frame (once).
+     */
+    fn close_connection(&mut self, code: CloseCode, reason: &str) -> Vec<TcpClientAction> {
+        if self.close_sent.is_some() {
+            return Vec::new();
+        }
+        self.close_sent = Some(Instant::now());
+        vec![TcpClientAction::SendData(build_close_frame(code, reason))]
+    }
+
+    /**
+     * Returns when the next heartbeat ping or close timeout is due.
+     */
+    fn next_tick(&self) -> Option<Instant> {
+        if let Some(close_sent) = self.close_sent {
+            return Some(close_sent + self.config.close_timeout);
+        }
+        self.config.ping_interval.map(|interval| self.last_ping + interval)
+    }
+
+    /**
+     * Sends heartbeat pings and drops clients that stop answering them.
+     */
+    fn handle_tick(&mut self) -> Vec<TcpClientAction> {
+        // Drop clients that never answer our close frame
+        if let Some(close_sent) = self.close_sent {
+            if close_sent.elapsed() >= self.config.close_timeout {
+                warn!(
+                    "[WebSocket Client] ({0}) Client did not answer close frame. Dropping connection.",
+                    &self.address
+                );
+                return vec![
+                    TcpClientAction::HandleClose(CloseCode::Abnormal, String::from("Close timed out")),
+                    TcpClientAction::CloseConnection(DisconnectReason::TimedOut),
+                ];
+            }
+            return Vec::new();
+        }
+
+        let ping_interval = match self.config.ping_interval {
+            Some(interval) => interval,
+            None => return Vec::new(),
+        };
+
+        if self.last_ping.elapsed() < ping_interval {
+            return Vec::new();
+        }
+
+        if self.missed_pongs >= self.config.max_missed_pongs {
+            warn!(
+                "[WebSocket Client] ({0}) Client missed {1} pongs. Dropping connection.",
+                &self.address, self.missed_pongs
+            );
+            return vec![
+                TcpClientAction::HandleClose(CloseCode::Abnormal, String::from("Heartbeat timed out")),
+                TcpClientAction::CloseConnection(DisconnectReason::TimedOut),
+            ];
+        }
+
+        debug!("[WebSocket Client] ({0}) Sending heartbeat ping.", &self.address);
+        self.last_ping = Instant::now();
+        self.missed_pongs += 1;
+        vec![TcpClientAction::SendData(build_websocket_frame(Opcode::Ping, true, &[]))]
+    }
 }
 
-/// Returns a byte-array containing a websocket frame.
-/// 
-/// WebSocket frame layout: https://tools.ietf.org/html/rfc6455#section-5.2
-/// 
-/// # Arguments
-/// 
-/// * `content` - The content of the websocket frame.
-fn build_websocket_frame(content: &str) -> Vec<u8> {
-    let mut byte1: u8 = 0; // Flags and opcode
-    let byte2: u8; // Mask and payload length
-
-    // Set op code to 1 (text)
-    byte1 |= 0b1000_0001;
-    
-    // TODO: Handle payloads longer than 126 bytes
-    // Set payload length
-    byte2 = content.len() as u8;
-
-    let mut result: Vec<u8> = Vec::new();
-
-    result.push(byte1);
-    result.push(byte2);
-    // result.push(masking_key[0]);
-    // result.push(masking_key[1]);
-    // result.push(masking_key[2]);
-    // result.push(masking_key[3]);
-    
-    let c = content.as_bytes();
-    for i in 0..content.len() {
-        result.push(c[i]);
-    }
-
-    result
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> WebSocketClientRequestHandler {
+        WebSocketClientRequestHandler::new("127.0.0.1:4000".parse().unwrap(), WebSocketConfig::default())
+    }
+
+    /// Builds a masked close frame with the given status code, as a client sends it.
+    fn client_close_frame(code: u16) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0b1000_1000, 0b1000_0010];
+        frame.extend_from_slice(&mask);
+        frame.extend(code.to_be_bytes().iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// Feeds bytes to the handler, returning what it sends and why it drops the connection.
+    fn receive(handler: &mut WebSocketClientRequestHandler, data: &[u8]) -> (Vec<u8>, Option<DisconnectReason>) {
+        let mut sent = Vec::new();
+        let mut closed = None;
+        for action in handler.handle_request(&mut Vec::new(), data, &data.len()) {
+            match action {
+                TcpClientAction::SendData(data) => sent.extend(data),
+                TcpClientAction::CloseConnection(reason) => closed = Some(reason),
+                TcpClientAction::HandleError(error) => closed = Some(DisconnectReason::Error(error.to_string())),
+                _ => {}
+            }
+        }
+        (sent, closed)
+    }
+
+    #[test]
+    fn sends_no_messages_after_the_close_frame() {
+        let mut handler = handler();
+        let mut stream: Vec<u8> = Vec::new();
+        handler.send_response(&mut stream, "Before").unwrap();
+        assert_eq!(stream, build_websocket_frame(Opcode::Text, true, b"Before"));
+
+        assert_eq!(handler.close_connection(CloseCode::Normal, "Bye").len(), 1);
+        stream.clear();
+        handler.send_response(&mut stream, "After").unwrap();
+        handler.send_binary_response(&mut stream, vec![1, 2, 3]).unwrap();
+        assert!(stream.is_empty());
+
+        // The close frame is only sent once
+        assert!(handler.close_connection(CloseCode::Normal, "Bye").is_empty());
+    }
+
+    #[test]
+    fn sends_no_messages_after_answering_the_clients_close_frame() {
+        let mut handler = handler();
+        let (sent, _) = receive(&mut handler, &client_close_frame(1000));
+        assert_eq!(sent, build_close_frame(CloseCode::Normal, ""));
+
+        let mut stream: Vec<u8> = Vec::new();
+        handler.send_response(&mut stream, "After").unwrap();
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn echoes_valid_close_codes() {
+        for code in [1000, 1001, 1003, 1011, 3000, 4999] {
+            let (sent, closed) = receive(&mut handler(), &client_close_frame(code));
+            assert_eq!(sent, build_close_frame(CloseCode::from_u16(code), ""), "code {}", code);
+            assert_eq!(closed, Some(DisconnectReason::ClosedByClient), "code {}", code);
+        }
+    }
+
+    #[test]
+    fn reports_protocol_violations_as_protocol_errors() {
+        let unmasked = build_websocket_frame(Opcode::Text, true, b"Hello");
+        let (sent, closed) = receive(&mut handler(), &unmasked);
+        assert_eq!(sent, build_close_frame(CloseCode::ProtocolError, "Unmasked frame"));
+        assert_eq!(closed, Some(DisconnectReason::Error(Error::Protocol(String::from("Unmasked frame")).to_string())));
+
+        let mut oversized_ping = vec![0b1000_1001, 0b1000_0000 | 126, 0, 126, 0, 0, 0, 0];
+        oversized_ping.extend_from_slice(&[0; 126]);
+        let (sent, _) = receive(&mut handler(), &oversized_ping);
+        assert_eq!(sent, build_close_frame(CloseCode::ProtocolError, "Oversized control frame"));
+    }
+
+    #[test]
+    fn fails_the_connection_on_invalid_close_codes() {
+        for code in [0, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            let (sent, closed) = receive(&mut handler(), &client_close_frame(code));
+            assert_eq!(sent, build_close_frame(CloseCode::ProtocolError, "Malformed close frame"), "code {}", code);
+            let reason = DisconnectReason::Error(Error::Protocol(String::from("Malformed close frame")).to_string());
+            assert_eq!(closed, Some(reason), "code {}", code);
+        }
+    }
+}
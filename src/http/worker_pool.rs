@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender, SendError, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use mio::{Events, Poll, Token, Waker};
+use super::tcp_client_handler::TcpClientHandler;
+use super::tcp_server::Request;
+
+/// Poll token used to wake a worker when the server has sent it something.
+const WAKER: Token = Token(0);
+
+/// Worker pool settings.
+#[derive(Clone, Debug)]
+pub struct WorkerPoolConfig {
+    /// Number of worker threads. Each worker multiplexes many connections.
+    pub worker_count: usize,
+    /// Most connections the server will hold open at once. Connections beyond this are
+    /// refused with `503 Service Unavailable`.
+    pub max_connections: usize,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            worker_count: std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(4),
+            max_connections: 10_000,
+        }
+    }
+}
+
+/// Messages sent from the server to a worker.
+pub enum WorkerMessage {
+    /// Take over a newly accepted client.
+    AddClient(Box<TcpClientHandler>),
+    /// Carry out a request for one of the worker's clients.
+    Request(Request),
+    /// Send a text message to several of the worker's clients. The clients share the message.
+    SendShared {
+        client_ids: Vec<String>,
+        message: Arc<str>
+    },
+    /// Stop the worker.
+    Shutdown,
+}
+
+/// Sends messages to a worker and wakes its event loop.
+#[derive(Clone)]
+pub struct Worker {
+    id: usize,
+    tx: Sender<WorkerMessage>,
+    waker: Arc<Waker>
+}
+
+impl Worker {
+    /// Identifies the worker within its pool.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn send(&self, message: WorkerMessage) -> Result<(), SendError<WorkerMessage>> {
+        self.tx.send(message)?;
+        if let Err(error) = self.waker.wake() {
+            warn!("[Worker] Error waking worker. Error: {0}", error);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * A fixed set of worker threads that share the server's connections between them.
+ */
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    threads: Vec<JoinHandle<()>>,
+    next_worker: usize
+}
+
+impl WorkerPool {
+    /**
+     * Starts the worker threads.
+     *
+     * Each read from a client goes into a buffer of `read_buffer_size` bytes, and workers
+     * sleep for at most `poll_interval` (if given) when there is nothing to do.
+     */
+    pub fn new(
+        config: &WorkerPoolConfig,
+        read_buffer_size: usize,
+        poll_interval: Option<Duration>
+    ) -> std::io::Result<WorkerPool> {
+        let mut workers = Vec::new();
+        let mut threads = Vec::new();
+
+        for id in 0..config.worker_count.max(1) {
+            let poll = Poll::new()?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+            let (tx, rx) = channel::<WorkerMessage>();
+
+            threads.push(
+                std::thread::Builder::new()
+                    .name(format!("worker-{0}", id))
+                    .spawn(move || run_worker(id, poll, rx, read_buffer_size, poll_interval))?
+            );
+            workers.push(Worker { id, tx, waker });
+        }
+
+        Ok(WorkerPool {
+            workers,
+            threads,
+            next_worker: 0
+        })
+    }
+
+    /**
+     * Hands a new client to the next worker (round robin).
+     *
+     * Returns the worker so the server can route later requests for the client to it.
+     */
+    pub fn assign(&mut self, client: TcpClientHandler) -> Result<Worker, SendError<WorkerMessage>> {
+        let worker = &self.workers[self.next_worker];
+        self.next_worker = (self.next_worker + 1) % self.workers.len();
+
+        worker.send(WorkerMessage::AddClient(Box::new(client)))?;
+        Ok(worker.clone())
+    }
+
+    /**
+     * Stops every worker and waits for their threads to finish.
+     */
+    pub fn shutdown(self) {
+        for worker in &self.workers {
+            // A worker that has already gone has nothing left to stop
+            let _ = worker.send(WorkerMessage::Shutdown);
+        }
+        for thread in self.threads {
+            if thread.join().is_err() {
+                warn!("[Worker] A worker thread panicked.");
+            }
+        }
+    }
+}
+
+/**
+ * Event loop for a single worker.
+ */
+fn run_worker(
+    id: usize,
+    mut poll: Poll,
+    rx: Receiver<WorkerMessage>,
+    read_buffer_size: usize,
+    poll_interval: Option<Duration>
+) {
+    debug!("[Worker {0}] Started.", id);
+
+    let mut clients: HashMap<Token, TcpClientHandler> = HashMap::new();
+    let mut tokens: HashMap<String, Token> = HashMap::new();
+    let mut next_token = WAKER.0 + 1;
+    let mut events = Events::with_capacity(1024);
+    let mut buffer = vec![0u8; read_buffer_size];
+    let mut worker_running = true;
+
+    while worker_running {
+        // Wake up in time for the earliest client tick
+        let next_tick = clients
+            .values()
+            .filter_map(|client| client.next_tick())
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let timeout = match (next_tick, poll_interval) {
+            (Some(next_tick), Some(interval)) => Some(next_tick.min(interval)),
+            (next_tick, interval) => next_tick.or(interval),
+        };
+
+        if let Err(error) = poll.poll(&mut events, timeout) {
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                warn!("[Worker {0}] Error polling for events. Error: {1}", id, error);
+            }
+            continue;
+        }
+
+        // Handle stream readiness
+        for event in events.iter() {
+            if event.token() == WAKER {
+                continue;
+            }
+            if let Some(client) = clients.get_mut(&event.token()) {
+                client.handle_event(poll.registry(), event, &mut buffer);
+            }
+        }
+
+        // Handle messages from the server
+        loop {
+            match rx.try_recv() {
+                Ok(WorkerMessage::AddClient(mut client)) => {
+                    let token = Token(next_token);
+                    next_token += 1;
+                    client.start(poll.registry(), token);
+                    tokens.insert(client.client_id().to_string(), token);
+                    clients.insert(token, *client);
+                }
+                Ok(WorkerMessage::Request(request)) => {
+                    match tokens.get(&request.client_id).and_then(|token| clients.get_mut(token)) {
+                        Some(client) => client.handle_server_request(poll.registry(), request),
+                        None => debug!(
+                            "[Worker {0}] Dropping request for unknown client {1}.",
+                            id, request.client_id
+                        ),
+                    }
+                }
+                Ok(WorkerMessage::SendShared { client_ids, message }) => {
+                    for client_id in client_ids {
+                        match tokens.get(&client_id).and_then(|token| clients.get_mut(token)) {
+                            Some(client) => client.send_message(poll.registry(), &message),
+                            None => debug!(
+                                "[Worker {0}] Dropping message for unknown client {1}.",
+                                id, client_id
+                            ),
+                        }
+                    }
+                }
+                Ok(WorkerMessage::Shutdown) | Err(TryRecvError::Disconnected) => {
+                    debug!("[Worker {0}] Received request to stop.", id);
+                    worker_running = false;
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        // Give clients whose tick is due a chance to do time-based work
+        let now = Instant::now();
+        for client in clients.values_mut() {
+            if client.next_tick().is_some_and(|deadline| deadline <= now) {
+                client.handle_tick(poll.registry());
+            }
+        }
+
+        // Finish clients that have disconnected and taken everything sent to them
+        let finished: Vec<Token> = clients
+            .iter()
+            .filter(|(_, client)| client.is_finished())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in finished {
+            if let Some(client) = clients.remove(&token) {
+                tokens.remove(client.client_id());
+                client.finish(poll.registry());
+            }
+        }
+    }
+
+    // Finish any clients still attached to the worker
+    for (_, client) in clients.drain() {
+        client.finish(poll.registry());
+    }
+
+    debug!("[Worker {0}] Stopped.", id);
+}
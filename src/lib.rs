@@ -0,0 +1,26 @@
+//! A simple HTTP/WebSocket server.
+//!
+//! Implement `ClientHandler` to be told about clients and their messages, then start a
+//! server with `TcpServer::builder()`. Reply to clients (or stop the server) through the
+//! returned `ServerHandle`, or a `ServerSender` given to the handler.
+
+extern crate base64;
+extern crate sha1;
+
+pub mod client_handler;
+pub mod error;
+pub mod http;
+
+pub use client_handler::ClientHandler;
+pub use error::Error;
+#[cfg(feature = "async")]
+pub use client_handler::AsyncClientHandler;
+pub use http::{
+    Action, ClientInfo, CloseCode, ConnectionInfo, DisconnectReason, Headers, HttpConfig,
+    HttpRequest, HttpResponse, Request, RouteRequest, Router, ServerConfig, ServerEvent,
+    ServerHandle, ServerSender, ShutdownStatus, TcpClientType, TcpServer, TcpServerBuilder,
+    WebSocketConfig, WorkerPoolConfig,
+};
+#[cfg(feature = "async")]
+pub use http::AsyncTcpServer;
+pub use http::{request, response, websocket_codec};
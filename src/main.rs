@@ -1,17 +1,12 @@
 extern crate banner;
-extern crate base64;
-extern crate colored;
 extern crate log4rs;
-extern crate sha1;
 
-mod client_handler;
-mod http;
 mod extimpl;
 
 use banner::{Banner, Color, HeaderLevel, Style};
 use extimpl::MyServerImpl;
-use http::{TcpServer, Request, Action};
-use log::{debug, info, LevelFilter, SetLoggerError};
+use rust_tcp_server::{TcpServer, ServerEvent};
+use log::{debug, error, info, warn, LevelFilter, SetLoggerError};
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
@@ -21,7 +16,6 @@ use log4rs::{
     encode::pattern::PatternEncoder,
     filter::threshold::ThresholdFilter,
 };
-use std::sync::mpsc::TryRecvError;
 
 fn main() {
     // Initialize logging
@@ -31,8 +25,9 @@ fn main() {
     print_title_banner();
 
     // Verify startup arguments
-    if std::env::args().len() != 3 {
-        println!("Usage: rusttcpclient ip port");
+    let use_async = std::env::args().nth(3).as_deref() == Some("async");
+    if std::env::args().len() != 3 && !(std::env::args().len() == 4 && use_async) {
+        println!("Usage: rusttcpclient ip port [async]");
         return;
     }
 
@@ -46,73 +41,136 @@ fn main() {
     
     print_startup_banner(&ip, &port);
 
-    // Channel to communicate with the servers
-    let (main_to_server_tx, main_to_server_rx) = std::sync::mpsc::channel::<Request>();
-    let (server_to_main_tx, server_to_main_rx) = std::sync::mpsc::channel::<String>();
+    let server_address = format!("{0}:{1}", &ip, &port);
 
-    // Create client handler
-    let my_server: MyServerImpl = MyServerImpl::new(String::from("MyServer"), main_to_server_tx);
+    // Run the async server instead if asked to
+    if use_async {
+        std::process::exit(run_async_server(server_address));
+    }
 
-    // Create server
-    let server_address = format!("{0}:{1}", &ip, &port);
-    let server: TcpServer = TcpServer {
-        address: server_address,
-        name: String::from("My Server"),
-        handler: Box::new(my_server),
-        main_to_server_rx: main_to_server_rx,
-        server_to_main_tx: server_to_main_tx,
-    };
-    // Start server
-    server.start();
-
-    // Server running flag
-    let mut server_running = true;
-
-    while server_running {
-        // Check for messages from server
-        match server_to_main_rx.try_recv() {
-            Ok(message) => {
-                debug!("[Main] Server said: {0}", message);
-
-                // Check for shutdown
-                if message == "Shutdown" {
-                    server_running = false;
-                }
-            }
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => {
-                panic!("[Main] Error receiving on server channel. Channel disconnected")
-            }
+    // Create and start server
+    let server = TcpServer::builder()
+        .address(&server_address)
+        .name("My Server")
+        .handler_with(|sender| MyServerImpl::new(String::from("MyServer"), sender).into_router())
+        .start();
+    let server = match server {
+        Ok(server) => server,
+        Err(error) => {
+            error!("[Main] Error starting server on {0}. Error: {1}", server_address, error);
+            println!("Could not start server on {0}: {1}", server_address, error);
+            std::process::exit(1);
         }
+    };
+    info!("[Main] Server listening on {0}.", server.local_addr());
 
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+    // Stop the server on Ctrl+C (SIGINT) or SIGTERM
+    let sender = server.sender();
+    let signal_handler = ctrlc::set_handler(move || {
+        info!("[Main] Received signal. Stopping server.");
+        if sender.stop().is_err() {
+            debug!("[Main] Server has already stopped.");
+        }
+    });
+    if let Err(error) = signal_handler {
+        warn!("[Main] Error setting signal handler. Error: {0}", error);
     }
 
-    // Tell server to shut down
-    info!("[Main] Sending shutdown message to server.");
-    // main_to_server_tx
-    //     .send(String::from("StopServer"))
-    //     .expect("[Main] Error communicating shutdown to server.");
+    // Wait for the server to report that it has stopped
+    if let Ok(ServerEvent::Stopped) = server.events().recv() {
+        debug!("[Main] Client server has stopped.");
+    }
 
-    let mut server_shutting_down = true;
     // Wait for server to shut down
-    while server_shutting_down {
-        match server_to_main_rx.try_recv() {
-            Ok(message) => {
-                if message == "ServerStopped" {
-                    server_shutting_down = false;
-                    debug!("[Main] Client server has stopped.");
-                }
+    let exit_code = match server.join() {
+        Ok(status) => {
+            info!("[Main] Server stopped ({0:?}). Quitting.", status);
+            status.exit_code()
+        }
+        Err(_) => {
+            error!("[Main] Server thread panicked.");
+            1
+        }
+    };
+    std::process::exit(exit_code);
+}
+
+/**
+ * Runs the async server until it stops and returns the process's exit code.
+ */
+#[cfg(feature = "async")]
+fn run_async_server(server_address: String) -> i32 {
+    use extimpl::MyAsyncServerImpl;
+    use rust_tcp_server::{
+        Action, AsyncTcpServer, HttpConfig, Request, ServerConfig, WebSocketConfig, WorkerPoolConfig,
+    };
+    use tokio::sync::mpsc::unbounded_channel;
+
+    let runtime = tokio::runtime::Runtime::new().expect("[Main] Error creating async runtime.");
+    runtime.block_on(async move {
+        // Channel to communicate with the servers
+        let (main_to_server_tx, main_to_server_rx) = unbounded_channel::<Request>();
+        let (server_to_main_tx, mut server_to_main_rx) = unbounded_channel::<ServerEvent>();
+
+        // Create client handler
+        let my_server = MyAsyncServerImpl::new(String::from("MyServer"), main_to_server_tx.clone()).into_router();
+
+        // Create server
+        let server = AsyncTcpServer {
+            address: server_address,
+            name: String::from("My Server"),
+            handler: my_server,
+            main_to_server_rx,
+            server_to_main_tx,
+            http_config: HttpConfig::default(),
+            websocket_config: WebSocketConfig::default(),
+            max_connections: WorkerPoolConfig::default().max_connections,
+            drain_timeout: ServerConfig::default().drain_timeout,
+        };
+        // Start server
+        let server_task = tokio::spawn(server.run());
+
+        // Stop the server on Ctrl+C (SIGINT) or SIGTERM
+        let stop_tx = main_to_server_tx.clone();
+        let signal_handler = ctrlc::set_handler(move || {
+            info!("[Main] Received signal. Stopping server.");
+            let stop = Request { client_id: String::new(), action: Action::Stop };
+            if stop_tx.send(stop).is_err() {
+                debug!("[Main] Server has already stopped.");
             }
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => {
-                debug!("[Main] Client server has disconnected.");
+        });
+        if let Err(error) = signal_handler {
+            warn!("[Main] Error setting signal handler. Error: {0}", error);
+        }
+
+        // Wait for the server to report that it has stopped
+        if let Some(ServerEvent::Stopped) = server_to_main_rx.recv().await {
+            debug!("[Main] Client server has stopped.");
+        }
+
+        // Wait for server to shut down
+        match server_task.await {
+            Ok(Ok(status)) => {
+                info!("[Main] Server stopped ({0:?}). Quitting.", status);
+                status.exit_code()
+            }
+            Ok(Err(error)) => {
+                error!("[Main] Error running server. Error: {0}", error);
+                println!("Could not run server: {0}", error);
+                1
+            }
+            Err(_) => {
+                error!("[Main] Server task panicked.");
+                1
             }
         }
-    }
+    })
+}
 
-    info!("[Main] Server stopped. Quitting.");
-    std::thread::sleep(std::time::Duration::from_millis(3000));
+#[cfg(not(feature = "async"))]
+fn run_async_server(_server_address: String) -> i32 {
+    println!("The async server requires building with the \"async\" feature.");
+    1
 }
 
 fn print_title_banner() {
@@ -0,0 +1,255 @@
+//! Helpers for running the server binary and talking to it over raw sockets.
+//!
+//! Each test crate uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CONTINUATION: u8 = 0x0;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+/// Which server the binary runs.
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    Sync,
+    Async,
+}
+
+/// A server process, killed when dropped.
+pub struct Server {
+    process: Child,
+    port: u16,
+    directory: std::path::PathBuf,
+}
+
+impl Server {
+    /// Starts the server binary on a free local port.
+    pub fn start(mode: Mode) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Error finding a free port.")
+            .port();
+
+        // The server logs to tmp/ under its working directory
+        let directory = std::env::temp_dir().join(format!("rusttcpserver-test-{}", port));
+        std::fs::create_dir_all(&directory).expect("Error creating working directory.");
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rust-tcp-server"));
+        command.args(["127.0.0.1", &port.to_string()]);
+        if let Mode::Async = mode {
+            command.arg("async");
+        }
+        let process = command
+            .current_dir(&directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Error starting server.");
+
+        Server { process, port, directory }
+    }
+
+    /// Opens a plain TCP connection, retrying while the server starts up.
+    pub fn connect(&self) -> TcpStream {
+        let started = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", self.port)) {
+                Ok(stream) => {
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .expect("Error setting read timeout.");
+                    return stream;
+                }
+                Err(error) if started.elapsed() > Duration::from_secs(10) => {
+                    panic!("Error connecting to server: {}", error)
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+
+    /// Opens a connection and upgrades it to a WebSocket.
+    pub fn connect_websocket(&self) -> WebSocketClient {
+        let mut stream = self.connect();
+        let response = upgrade(&mut stream, "dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(response.starts_with("HTTP/1.1 101"), "Upgrade was rejected: {}", response);
+        WebSocketClient { stream }
+    }
+
+    /// Sends SIGTERM to the server and waits (up to ten seconds) for it to exit.
+    #[cfg(unix)]
+    pub fn terminate(&mut self) -> std::process::ExitStatus {
+        let sent = Command::new("kill")
+            .args(["-TERM", &self.process.id().to_string()])
+            .status()
+            .expect("Error sending SIGTERM.");
+        assert!(sent.success(), "kill failed.");
+        self.wait()
+    }
+
+    /// Waits (up to ten seconds) for the server to exit.
+    pub fn wait(&mut self) -> std::process::ExitStatus {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.process.try_wait().expect("Error waiting for server.") {
+                return status;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "Server did not exit.");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Sends a WebSocket upgrade request with the given key and returns the response head.
+pub fn upgrade(stream: &mut TcpStream, key: &str) -> String {
+    let request = format!(
+        "GET /chat HTTP/1.1\r\n\
+        Host: 127.0.0.1\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Key: {}\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n",
+        key
+    );
+    stream.write_all(request.as_bytes()).expect("Error sending upgrade request.");
+
+    let mut response: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).expect("Error reading upgrade response.");
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).expect("Upgrade response is not UTF-8.")
+}
+
+/// Reads one HTTP response and returns its head and body.
+pub fn read_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut head: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).expect("Error reading response head.");
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).expect("Response head is not UTF-8.");
+
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .map_or(0, |(_, value)| value.trim().parse().expect("Invalid Content-Length."));
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).expect("Error reading response body.");
+    (head, body)
+}
+
+/// A frame received from the server.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// The client end of a WebSocket connection.
+pub struct WebSocketClient {
+    pub stream: TcpStream,
+}
+
+impl WebSocketClient {
+    /// Sends a single frame.
+    pub fn send_frame(&mut self, opcode: u8, fin: bool, payload: &[u8]) {
+        self.stream.write_all(&frame(opcode, fin, payload)).expect("Error sending frame.");
+    }
+
+    pub fn send_text(&mut self, message: &str) {
+        self.send_frame(TEXT, true, message.as_bytes());
+    }
+
+    /// Reads the next frame from the server.
+    pub fn read_frame(&mut self) -> Frame {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).expect("Error reading frame header.");
+        let length = match header[1] & 0b0111_1111 {
+            126 => {
+                let mut length = [0u8; 2];
+                self.stream.read_exact(&mut length).expect("Error reading frame length.");
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                self.stream.read_exact(&mut length).expect("Error reading frame length.");
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        assert_eq!(header[1] & 0b1000_0000, 0, "Server frames must not be masked.");
+
+        let mut payload = vec![0u8; length];
+        self.stream.read_exact(&mut payload).expect("Error reading frame payload.");
+        Frame {
+            fin: header[0] & 0b1000_0000 != 0,
+            opcode: header[0] & 0b0000_1111,
+            payload,
+        }
+    }
+
+    /// Reads the next frame, expecting it to be a complete text message.
+    pub fn read_text(&mut self) -> String {
+        let frame = self.read_frame();
+        assert_eq!(frame.opcode, TEXT);
+        assert!(frame.fin);
+        String::from_utf8(frame.payload).expect("Message is not UTF-8.")
+    }
+
+    /// Reads the next frame, expecting it to be a close frame, and returns its status code
+    /// and reason.
+    pub fn read_close(&mut self) -> (u16, String) {
+        let frame = self.read_frame();
+        assert_eq!(frame.opcode, CLOSE);
+        assert!(frame.payload.len() >= 2, "Close frame has no status code.");
+        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+        let reason = String::from_utf8(frame.payload[2..].to_vec()).expect("Reason is not UTF-8.");
+        (code, reason)
+    }
+
+    /// Returns whether the server has closed the connection.
+    pub fn is_closed_by_server(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        matches!(self.stream.read(&mut byte), Ok(0))
+    }
+}
+
+/// Builds a masked client frame.
+pub fn frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![if fin { 0b1000_0000 } else { 0 } | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(0b1000_0000 | length as u8),
+        length if length < 65536 => {
+            frame.push(0b1000_0000 | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(0b1000_0000 | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    frame
+}
@@ -0,0 +1,400 @@
+//! Settings and queries of the library API, tested against the threaded server started in
+//! process.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::collections::HashSet;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, SystemTime};
+use common::{read_response, upgrade, WebSocketClient, PING, PONG};
+use rust_tcp_server::{
+    Action, ClientHandler, ConnectionInfo, DisconnectReason, Error, HttpRequest, HttpResponse, ServerHandle, TcpClientType,
+    TcpServer, TcpServerBuilder,
+};
+
+/// What the application was told about a client.
+#[derive(Debug)]
+enum Noted {
+    Connected(String, ConnectionInfo),
+    Upgraded(String, ConnectionInfo),
+    Disconnected(String, DisconnectReason),
+    /// The error, as displayed.
+    Error(String, String),
+}
+
+/// Size of the body `Recorder` answers `/large` with.
+const LARGE_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Passes everything the server tells it on to the test.
+struct Recorder {
+    tx: Sender<Noted>,
+}
+
+impl ClientHandler for Recorder {
+    fn on_client_connected(&self, client_id: &str, connection: &ConnectionInfo) {
+        let _ = self.tx.send(Noted::Connected(String::from(client_id), connection.clone()));
+    }
+
+    fn on_message_received(&self, _client_id: &str, _message: &str) {}
+
+    fn on_websocket_upgraded(&self, client_id: &str, connection: &ConnectionInfo, _request: &HttpRequest) {
+        let _ = self.tx.send(Noted::Upgraded(String::from(client_id), connection.clone()));
+    }
+
+    /// Answers `/large` with a body too big for the socket to take at once.
+    fn on_http_request(&self, _client_id: &str, _connection: &ConnectionInfo, request: &HttpRequest) -> Option<HttpResponse> {
+        match request.path.as_str() {
+            "/large" => Some(HttpResponse::new(200).with_body("application/octet-stream", vec![7u8; LARGE_BODY_SIZE])),
+            _ => Some(HttpResponse::not_found()),
+        }
+    }
+
+    fn on_client_disconnected(&self, client_id: &str, reason: &DisconnectReason) {
+        let _ = self.tx.send(Noted::Disconnected(String::from(client_id), reason.clone()));
+    }
+
+    fn on_error(&self, client_id: &str, error: &Error) {
+        let _ = self.tx.send(Noted::Error(String::from(client_id), error.to_string()));
+    }
+}
+
+/// Starts a server on a free local port with the given settings and a `Recorder`.
+fn start(builder: TcpServerBuilder) -> (ServerHandle, Receiver<Noted>) {
+    let (tx, rx) = channel();
+    let server = builder
+        .address("127.0.0.1:0")
+        .handler(Recorder { tx })
+        .start()
+        .expect("Error starting server.");
+    (server, rx)
+}
+
+/// Opens a connection to the server.
+fn connect(server: &ServerHandle) -> TcpStream {
+    let stream = TcpStream::connect(server.local_addr()).expect("Error connecting to server.");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).expect("Error setting read timeout.");
+    stream
+}
+
+/// Returns whether the server has closed the connection.
+fn is_closed_by_server(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(stream.read(&mut byte), Ok(0))
+}
+
+#[test]
+fn answers_slow_requests_with_request_timeout() {
+    let (server, _) = start(TcpServer::builder().request_timeout(Duration::from_millis(200)));
+    let mut stream = connect(&server);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n").unwrap();
+
+    let (head, _) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", head);
+    assert!(is_closed_by_server(&mut stream));
+}
+
+#[test]
+fn closes_idle_connections() {
+    let (server, notes) = start(TcpServer::builder().request_timeout(Duration::from_millis(200)));
+    let mut stream = connect(&server);
+
+    // Nothing was asked, so nothing is answered
+    assert!(is_closed_by_server(&mut stream));
+    let connected = match notes.recv().unwrap() {
+        Noted::Connected(client_id, connection) => {
+            assert_eq!(connection.peer_address, stream.local_addr().unwrap());
+            client_id
+        }
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+    match notes.recv().unwrap() {
+        Noted::Disconnected(client_id, reason) => {
+            assert_eq!(client_id, connected);
+            assert_eq!(reason, DisconnectReason::TimedOut);
+        }
+        noted => panic!("Expected a disconnect, got {:?}", noted),
+    }
+}
+
+#[test]
+fn refuses_a_zero_read_buffer_size() {
+    let (tx, _rx) = channel();
+    let started = TcpServer::builder()
+        .address("127.0.0.1:0")
+        .read_buffer_size(0)
+        .handler(Recorder { tx })
+        .start();
+    assert!(matches!(started, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn multicasts_only_to_websocket_clients() {
+    let (server, notes) = start(TcpServer::builder());
+    let mut stream = connect(&server);
+    let client_id = match notes.recv().unwrap() {
+        Noted::Connected(client_id, _) => client_id,
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+
+    // A text frame would corrupt the plain HTTP connection, so it is not sent
+    server.sender().multicast(vec![client_id], "Hello").unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+}
+
+#[test]
+fn refuses_to_put_plain_http_clients_in_rooms() {
+    let (server, notes) = start(TcpServer::builder());
+    let _stream = connect(&server);
+    let client_id = match notes.recv().unwrap() {
+        Noted::Connected(client_id, _) => client_id,
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+
+    server.sender().join_room(&client_id, "lobby").unwrap();
+    match notes.recv().unwrap() {
+        Noted::Error(errored, error) => {
+            assert_eq!(errored, client_id);
+            assert_eq!(error, Error::NotWebSocket(client_id).to_string());
+        }
+        noted => panic!("Expected an error, got {:?}", noted),
+    }
+}
+
+#[test]
+fn lists_the_connected_clients() {
+    let (server, notes) = start(TcpServer::builder());
+    let streams = [connect(&server), connect(&server)];
+    let mut connected: Vec<(String, ConnectionInfo)> = (0..streams.len())
+        .map(|_| match notes.recv().unwrap() {
+            Noted::Connected(client_id, connection) => (client_id, connection),
+            noted => panic!("Expected a connect, got {:?}", noted),
+        })
+        .collect();
+
+    let mut listed: Vec<(String, ConnectionInfo)> = server
+        .list_clients()
+        .unwrap()
+        .into_iter()
+        .inspect(|client| assert!(client.is_connected))
+        .map(|client| (client.client_id, client.connection))
+        .collect();
+    connected.sort_by(|a, b| a.0.cmp(&b.0));
+    listed.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(listed, connected);
+}
+
+#[test]
+fn reports_requests_for_unknown_clients_to_the_handler() {
+    let (server, notes) = start(TcpServer::builder());
+
+    server.sender().send("missing", Action::SendMessage(String::from("Hello"))).unwrap();
+    match notes.recv().unwrap() {
+        Noted::Error(client_id, error) => {
+            assert_eq!(client_id, "missing");
+            assert_eq!(error, Error::UnknownClient(String::from("missing")).to_string());
+        }
+        noted => panic!("Expected an error, got {:?}", noted),
+    }
+}
+
+#[test]
+fn drops_websocket_clients_that_miss_pongs() {
+    let (server, notes) = start(TcpServer::builder().ping_interval(Some(Duration::from_millis(100))));
+    let mut stream = connect(&server);
+    let response = upgrade(&mut stream, "dGhlIHNhbXBsZSBub25jZQ==");
+    assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+    let mut client = WebSocketClient { stream };
+
+    // Two pings may go unanswered (the default), the connection is dropped at the third
+    for _ in 0..2 {
+        assert_eq!(client.read_frame().opcode, PING);
+    }
+    assert!(client.is_closed_by_server());
+
+    let connected = match notes.recv().unwrap() {
+        Noted::Connected(client_id, _) => client_id,
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+    assert!(matches!(notes.recv().unwrap(), Noted::Upgraded(..)));
+    match notes.recv().unwrap() {
+        Noted::Disconnected(client_id, reason) => {
+            assert_eq!(client_id, connected);
+            assert_eq!(reason, DisconnectReason::TimedOut);
+        }
+        noted => panic!("Expected a disconnect, got {:?}", noted),
+    }
+}
+
+#[test]
+fn keeps_websocket_clients_that_answer_pings() {
+    let (server, _) = start(TcpServer::builder().ping_interval(Some(Duration::from_millis(100))));
+    let mut stream = connect(&server);
+    upgrade(&mut stream, "dGhlIHNhbXBsZSBub25jZQ==");
+    let mut client = WebSocketClient { stream };
+
+    for _ in 0..4 {
+        let ping = client.read_frame();
+        assert_eq!(ping.opcode, PING);
+        client.send_frame(PONG, true, &ping.payload);
+    }
+    client.send_text("Still here");
+    assert_eq!(client.read_frame().opcode, PING);
+}
+
+#[test]
+fn refuses_connections_over_the_limit_with_service_unavailable() {
+    let (server, notes) = start(TcpServer::builder().max_connections(1));
+    let first = connect(&server);
+    let first_id = match notes.recv().unwrap() {
+        Noted::Connected(client_id, _) => client_id,
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+
+    let mut refused = connect(&server);
+    let (head, _) = read_response(&mut refused);
+    assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", head);
+    assert!(is_closed_by_server(&mut refused));
+
+    // The refused connection was never a client, and its place is free again once the first
+    // client leaves
+    drop(first);
+    match notes.recv().unwrap() {
+        Noted::Disconnected(client_id, _) => assert_eq!(client_id, first_id),
+        noted => panic!("Expected a disconnect, got {:?}", noted),
+    }
+    let mut stream = connect(&server);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+}
+
+#[test]
+fn reports_the_port_it_bound() {
+    let (server, notes) = start(TcpServer::builder());
+    let address = server.local_addr();
+    assert_eq!(address.ip().to_string(), "127.0.0.1");
+    assert_ne!(address.port(), 0);
+
+    let _stream = connect(&server);
+    assert!(matches!(notes.recv().unwrap(), Noted::Connected(..)));
+}
+
+#[test]
+fn fails_to_start_on_a_port_in_use() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (tx, _rx) = channel();
+    let started = TcpServer::builder()
+        .address(&listener.local_addr().unwrap().to_string())
+        .handler(Recorder { tx })
+        .start();
+    match started {
+        Err(Error::Io(error)) => assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse),
+        Err(error) => panic!("Expected an I/O error, got {:?}", error),
+        Ok(_) => panic!("Server started on a port in use."),
+    }
+}
+
+#[test]
+fn gives_clients_opaque_ids_that_are_never_reused() {
+    let (server, notes) = start(TcpServer::builder());
+    let mut ids: HashSet<String> = HashSet::new();
+    for _ in 0..5 {
+        let stream = connect(&server);
+        let address = stream.local_addr().unwrap();
+        let client_id = match notes.recv().unwrap() {
+            Noted::Connected(client_id, _) => client_id,
+            noted => panic!("Expected a connect, got {:?}", noted),
+        };
+        // The id doesn't give the client's address away
+        assert!(!client_id.contains(&address.ip().to_string()), "{}", client_id);
+        assert!(!client_id.contains(&address.to_string()), "{}", client_id);
+        assert!(ids.insert(client_id.clone()), "Id {} was given twice", client_id);
+
+        // The next client gets a new id even though this one has left
+        drop(stream);
+        match notes.recv().unwrap() {
+            Noted::Disconnected(disconnected, _) => assert_eq!(disconnected, client_id),
+            noted => panic!("Expected a disconnect, got {:?}", noted),
+        }
+    }
+}
+
+#[test]
+fn describes_client_connections() {
+    let (server, notes) = start(TcpServer::builder());
+    let before = SystemTime::now();
+    let mut stream = connect(&server);
+    let (client_id, connection) = match notes.recv().unwrap() {
+        Noted::Connected(client_id, connection) => (client_id, connection),
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+    let after = SystemTime::now();
+    assert_eq!(connection.peer_address, stream.local_addr().unwrap());
+    assert!(before <= connection.connected_at && connection.connected_at <= after);
+    assert_eq!(connection.protocol, TcpClientType::Http);
+    assert_eq!(connection.path, None);
+
+    // Upgrading changes the protocol and records the path the client asked for
+    upgrade(&mut stream, "dGhlIHNhbXBsZSBub25jZQ==");
+    let upgraded = match notes.recv().unwrap() {
+        Noted::Upgraded(upgraded_id, upgraded) => {
+            assert_eq!(upgraded_id, client_id);
+            upgraded
+        }
+        noted => panic!("Expected an upgrade, got {:?}", noted),
+    };
+    assert_eq!(upgraded.peer_address, connection.peer_address);
+    assert_eq!(upgraded.connected_at, connection.connected_at);
+    assert_eq!(upgraded.protocol, TcpClientType::WebSocket);
+    assert_eq!(upgraded.path.as_deref(), Some("/chat"));
+
+    let clients = server.list_clients().unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].client_id, client_id);
+    assert_eq!(clients[0].connection, upgraded);
+}
+
+#[test]
+fn sends_the_whole_response_before_closing_the_connection() {
+    let (server, _) = start(TcpServer::builder());
+    let mut stream = connect(&server);
+    stream.write_all(b"GET /large HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n").unwrap();
+
+    // Let the socket buffers fill up, so the server has to wait for the client to read
+    std::thread::sleep(Duration::from_millis(200));
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert_eq!(body.len(), LARGE_BODY_SIZE);
+    assert!(is_closed_by_server(&mut stream));
+}
+
+#[test]
+fn refuses_to_send_messages_to_plain_http_clients() {
+    let (server, notes) = start(TcpServer::builder());
+    let mut stream = connect(&server);
+    let client_id = match notes.recv().unwrap() {
+        Noted::Connected(client_id, _) => client_id,
+        noted => panic!("Expected a connect, got {:?}", noted),
+    };
+
+    server.sender().send(&client_id, Action::SendMessage(String::from("Hello"))).unwrap();
+    server.sender().send(&client_id, Action::SendBinary(vec![1, 2, 3])).unwrap();
+    for _ in 0..2 {
+        match notes.recv().unwrap() {
+            Noted::Error(errored, error) => {
+                assert_eq!(errored, client_id);
+                assert_eq!(error, Error::NotWebSocket(client_id.clone()).to_string());
+            }
+            noted => panic!("Expected an error, got {:?}", noted),
+        }
+    }
+
+    // Nothing was written into the HTTP stream
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+}
@@ -0,0 +1,531 @@
+//! Behavior shared by the threaded and the async server. Every test runs against both.
+
+mod common;
+
+macro_rules! server_tests {
+    ($mode:expr) => {
+        use std::io::{Read, Write};
+        use super::common::*;
+
+        #[test]
+        fn answers_plain_http_requests() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+                .unwrap();
+
+            let mut response = [0u8; 15];
+            stream.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"HTTP/1.1 200 OK");
+        }
+
+        #[test]
+        fn rejects_malformed_http_requests_without_disturbing_other_clients() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            let mut stream = server.connect();
+            stream.write_all(b"GARBAGE\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+            client.send_text("Hello");
+            assert_eq!(client.read_text(), "Echo: Hello");
+        }
+
+        #[test]
+        fn sends_complete_responses() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+                .unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(head.contains("\r\nContent-Length: 0\r\n"));
+            assert!(head.contains("\r\nDate: "));
+            assert!(body.is_empty());
+        }
+
+        #[test]
+        fn answers_requests_sent_in_pieces() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            for piece in ["GET / HT", "TP/1.1\r\nHo", "st: 127.0.0.1:80\r", "\n\r\n"] {
+                stream.write_all(piece.as_bytes()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+
+            let mut response = [0u8; 15];
+            stream.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"HTTP/1.1 200 OK");
+        }
+
+        #[test]
+        fn reads_chunked_bodies_before_the_next_request() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            let pieces = [
+                "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+                "lo\r\n6;name=value\r\n world\r\n0\r\n\r\n",
+                "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+            ];
+            for piece in pieces {
+                stream.write_all(piece.as_bytes()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+
+            for _ in 0..2 {
+                let (head, body) = read_response(&mut stream);
+                assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert!(body.is_empty());
+            }
+        }
+
+        #[test]
+        fn asks_for_the_body_when_the_client_expects_100_continue() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(b"POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+                .unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 100 Continue\r\n"), "{}", head);
+            assert!(body.is_empty());
+
+            stream.write_all(b"hello").unwrap();
+            let (head, _) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        }
+
+        #[test]
+        fn passes_http_requests_to_the_application() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"POST /echo HTTP/1.1\r\nHost: 127.0.0.1\r\n\
+                    Content-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello",
+                )
+                .unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(head.contains("\r\nContent-Type: text/plain\r\n"));
+            assert_eq!(body, b"hello");
+        }
+
+        #[test]
+        fn answers_pipelined_requests_in_order_when_responses_are_deferred() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /later HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                    GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                )
+                .unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert_eq!(body, b"Sorry for the wait");
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(body.is_empty());
+        }
+
+        #[test]
+        fn closes_the_connection_after_answering_connection_close() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\r\nConnection: close\r\n"));
+        }
+
+        #[test]
+        fn routes_requests_with_path_and_query_parameters() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /users/42?greeting=Hi+there HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                    GET /files/css/site.css HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                )
+                .unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert_eq!(body, b"Hi there, user 42");
+            let (_, body) = read_response(&mut stream);
+            assert_eq!(body, b"File: css/site.css");
+        }
+
+        #[test]
+        fn answers_unrouted_requests_with_not_found_or_method_not_allowed() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /nowhere HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                    DELETE /users/42 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                )
+                .unwrap();
+
+            let (head, _) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+            let (head, _) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+            assert!(head.contains("\r\nAllow: GET, HEAD\r\n"));
+        }
+
+        #[test]
+        fn refuses_websocket_upgrades_on_unrouted_paths() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /elsewhere HTTP/1.1\r\n\
+                    Host: 127.0.0.1\r\n\
+                    Connection: Upgrade\r\n\
+                    Upgrade: websocket\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .unwrap();
+
+            let (head, _) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        }
+
+        #[test]
+        fn decodes_path_segments_after_splitting_them() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /users/a%2Fb HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                    GET /files/a%20b/c.txt HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                    GET /files/..%2F..%2Fetc%2Fpasswd HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                )
+                .unwrap();
+
+            let (_, body) = read_response(&mut stream);
+            assert_eq!(body, b"Hello, user a/b");
+            let (_, body) = read_response(&mut stream);
+            assert_eq!(body, b"File: a b/c.txt");
+            let (head, _) = read_response(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+        }
+
+        #[test]
+        fn refuses_invalid_websocket_handshakes() {
+            let server = Server::start($mode);
+            let handshake = |method: &str, key: Option<&str>, version: &str| {
+                let mut stream = server.connect();
+                let key = key.map_or(String::new(), |key| format!("Sec-WebSocket-Key: {}\r\n", key));
+                let request = format!(
+                    "{} /chat HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                    {}Sec-WebSocket-Version: {}\r\n\r\n",
+                    method, key, version
+                );
+                stream.write_all(request.as_bytes()).unwrap();
+                read_response(&mut stream).0
+            };
+
+            let head = handshake("GET", None, "13");
+            assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", head);
+            let head = handshake("GET", Some("c2hvcnQ="), "13");
+            assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", head);
+            let head = handshake("POST", Some("dGhlIHNhbXBsZSBub25jZQ=="), "13");
+            assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", head);
+            let head = handshake("GET", Some("dGhlIHNhbXBsZSBub25jZQ=="), "8");
+            assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", head);
+            assert!(head.contains("\r\nSec-WebSocket-Version: 13\r\n"), "{}", head);
+        }
+
+        #[test]
+        fn rejects_bodies_over_the_size_limit() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(b"POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 1073741824\r\n\r\n")
+                .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        }
+
+        #[test]
+        fn rejects_unsupported_http_versions() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream.write_all(b"GET / HTTP/2.0\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        }
+
+        #[test]
+        fn matches_upgrade_headers_in_any_case() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            stream
+                .write_all(
+                    b"GET /chat HTTP/1.1\r\n\
+                    host: 127.0.0.1\r\n\
+                    connection: keep-alive, Upgrade\r\n\
+                    upgrade: WebSocket\r\n\
+                    sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    sec-websocket-version: 13\r\n\r\n",
+                )
+                .unwrap();
+
+            let mut response = [0u8; 34];
+            stream.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"HTTP/1.1 101 Switching Protocols\r\n");
+        }
+
+        #[test]
+        fn accepts_websocket_upgrades() {
+            let server = Server::start($mode);
+            let mut stream = server.connect();
+            let response = upgrade(&mut stream, "dGhlIHNhbXBsZSBub25jZQ==");
+
+            assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        }
+
+        #[test]
+        fn echoes_text_messages() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_text("Hello");
+            assert_eq!(client.read_text(), "Echo: Hello");
+        }
+
+        #[test]
+        fn echoes_messages_with_extended_lengths() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            for length in [300, 70_000] {
+                let message = "x".repeat(length);
+                client.send_text(&message);
+                assert_eq!(client.read_text(), format!("Echo: {}", message));
+            }
+        }
+
+        #[test]
+        fn echoes_binary_messages() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_frame(BINARY, true, &[0x00, 0xff, 0x01, 0xfe]);
+            let frame = client.read_frame();
+            assert_eq!(frame.opcode, BINARY);
+            assert_eq!(frame.payload, vec![0x00, 0xff, 0x01, 0xfe]);
+        }
+
+        #[test]
+        fn handles_coalesced_frames() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            let mut frames = frame(TEXT, true, b"one");
+            frames.extend(frame(TEXT, true, b"two"));
+            client.stream.write_all(&frames).unwrap();
+            assert_eq!(client.read_text(), "Echo: one");
+            assert_eq!(client.read_text(), "Echo: two");
+        }
+
+        #[test]
+        fn reassembles_fragmented_messages() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_frame(TEXT, false, b"Hel");
+            client.send_frame(PING, true, b"mid");
+            client.send_frame(CONTINUATION, true, b"lo");
+
+            let pong = client.read_frame();
+            assert_eq!((pong.opcode, pong.payload), (PONG, b"mid".to_vec()));
+            assert_eq!(client.read_text(), "Echo: Hello");
+        }
+
+        #[test]
+        fn answers_pings() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_frame(PING, true, b"abc");
+            let pong = client.read_frame();
+            assert_eq!((pong.opcode, pong.payload), (PONG, b"abc".to_vec()));
+        }
+
+        #[test]
+        fn completes_closing_handshake_started_by_client() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            let mut payload = 1000u16.to_be_bytes().to_vec();
+            payload.extend_from_slice(b"bye");
+            client.send_frame(CLOSE, true, &payload);
+
+            assert_eq!(client.read_close().0, 1000);
+            assert!(client.is_closed_by_server());
+        }
+
+        #[test]
+        fn closes_connection_at_application_request() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_text("Close");
+            assert_eq!(client.read_close(), (1000, String::from("Goodbye")));
+        }
+
+        #[test]
+        fn rejects_invalid_utf8() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.send_frame(TEXT, true, &[0xff, 0xfe]);
+            assert_eq!(client.read_close().0, 1007);
+        }
+
+        #[test]
+        fn rejects_unmasked_frames() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            client.stream.write_all(&[0b1000_0001, 2, b'h', b'i']).unwrap();
+            assert_eq!(client.read_close().0, 1002);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn shuts_down_gracefully_on_sigterm() {
+            let mut server = Server::start($mode);
+            let mut client = server.connect_websocket();
+            client.send_text("Hello");
+            assert_eq!(client.read_text(), "Echo: Hello");
+
+            let stopping = std::thread::spawn(move || server.terminate());
+
+            assert_eq!(client.read_close(), (1001, String::from("Server shutting down")));
+            client.send_frame(CLOSE, true, &1001u16.to_be_bytes());
+            assert!(client.is_closed_by_server());
+            assert!(stopping.join().unwrap().success());
+        }
+
+        #[test]
+        fn stops_when_the_application_asks() {
+            let mut server = Server::start($mode);
+            let mut client = server.connect_websocket();
+
+            // The example handler stops the server on this command
+            client.send_text("ShutdownServer");
+
+            assert_eq!(client.read_close(), (1001, String::from("Server shutting down")));
+            client.send_frame(CLOSE, true, &1001u16.to_be_bytes());
+            assert!(client.is_closed_by_server());
+            assert!(server.wait().success());
+        }
+
+        #[test]
+        fn relays_broadcasts_to_every_other_client() {
+            let server = Server::start($mode);
+            let mut sender = server.connect_websocket();
+            let mut others = [server.connect_websocket(), server.connect_websocket()];
+
+            sender.send_text("Broadcast Hello everyone");
+            for client in others.iter_mut() {
+                assert_eq!(client.read_text(), "Hello everyone");
+            }
+
+            // The sender gets nothing back, so the next message it reads is this echo
+            sender.send_text("Hello");
+            assert_eq!(sender.read_text(), "Echo: Hello");
+        }
+
+        #[test]
+        fn answers_with_the_number_of_clients() {
+            let server = Server::start($mode);
+            let mut client = server.connect_websocket();
+            let _other = server.connect_websocket();
+
+            client.send_text("Clients");
+            assert_eq!(client.read_text(), "Clients: 2");
+        }
+
+        #[test]
+        fn publishes_to_the_clients_in_a_room() {
+            let server = Server::start($mode);
+            let mut members = [server.connect_websocket(), server.connect_websocket()];
+            let mut outsider = server.connect_websocket();
+
+            // The echo comes back once the server has handled the join
+            for member in members.iter_mut() {
+                member.send_text("Join lobby");
+                member.send_text("Hello");
+                assert_eq!(member.read_text(), "Echo: Hello");
+            }
+
+            outsider.send_text("Publish lobby Welcome");
+            for member in members.iter_mut() {
+                assert_eq!(member.read_text(), "Welcome");
+            }
+
+            // After leaving, only the remaining member hears from the room
+            members[0].send_text("Leave lobby");
+            members[0].send_text("Hello");
+            assert_eq!(members[0].read_text(), "Echo: Hello");
+            outsider.send_text("Publish lobby Again");
+            assert_eq!(members[1].read_text(), "Again");
+            members[0].send_text("Hello");
+            assert_eq!(members[0].read_text(), "Echo: Hello");
+
+            // The outsider was never in the room
+            outsider.send_text("Hello");
+            assert_eq!(outsider.read_text(), "Echo: Hello");
+        }
+
+        #[test]
+        fn keeps_serving_other_clients_after_a_disconnect() {
+            let server = Server::start($mode);
+            let first = server.connect_websocket();
+            let mut second = server.connect_websocket();
+
+            drop(first);
+            std::thread::sleep(std::time::Duration::from_millis(200));
+
+            second.send_text("still there");
+            assert_eq!(second.read_text(), "Echo: still there");
+            let mut third = server.connect_websocket();
+            third.send_text("new");
+            assert_eq!(third.read_text(), "Echo: new");
+        }
+    };
+}
+
+mod sync_server {
+    server_tests!(Mode::Sync);
+}
+
+#[cfg(feature = "async")]
+mod async_server {
+    server_tests!(Mode::Async);
+}